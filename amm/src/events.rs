@@ -0,0 +1,89 @@
+//! NEP-297 events of the factory, in the same `amm` standard as the events of its pools.
+//!
+//! <https://github.com/near/NEPs/blob/master/specs/Standards/EventsFormat.md>
+
+use near_sdk::json_types::Base58CryptoHash;
+use near_sdk::serde::Serialize;
+use near_sdk::{env, serde_json, AccountId};
+
+pub const AMM_STANDARD: &str = "amm";
+pub const AMM_VERSION: &str = "1.0.0";
+
+#[must_use]
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolCreated<'a> {
+    pub pool_id: &'a AccountId,
+    pub a: &'a AccountId,
+    pub b: &'a AccountId,
+    pub fee: u32,
+    pub code_hash: &'a Base58CryptoHash,
+}
+
+#[must_use]
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolUpgraded<'a> {
+    pub pool_id: &'a AccountId,
+    pub code_hash: &'a Base58CryptoHash,
+}
+
+/// The factory stopped or resumed creating pools.
+#[must_use]
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Paused {
+    pub paused: bool,
+}
+
+#[derive(Serialize)]
+#[serde(
+    crate = "near_sdk::serde",
+    tag = "event",
+    content = "data",
+    rename_all = "snake_case"
+)]
+enum AmmEventKind<'a> {
+    PoolCreated(&'a [PoolCreated<'a>]),
+    PoolUpgraded(&'a [PoolUpgraded<'a>]),
+    Paused(&'a [Paused]),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct AmmEvent<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event_kind: AmmEventKind<'a>,
+}
+
+fn emit(event_kind: AmmEventKind) {
+    let event = AmmEvent {
+        standard: AMM_STANDARD,
+        version: AMM_VERSION,
+        event_kind,
+    };
+    env::log_str(&format!(
+        "EVENT_JSON:{}",
+        serde_json::to_string(&event).unwrap_or_else(|_| env::abort())
+    ));
+}
+
+impl PoolCreated<'_> {
+    pub fn emit(self) {
+        emit(AmmEventKind::PoolCreated(&[self]))
+    }
+}
+
+impl PoolUpgraded<'_> {
+    pub fn emit(self) {
+        emit(AmmEventKind::PoolUpgraded(&[self]))
+    }
+}
+
+impl Paused {
+    pub fn emit(self) {
+        emit(AmmEventKind::Paused(&[self]))
+    }
+}
@@ -5,15 +5,42 @@ use near_contract_standards::fungible_token::metadata::{
 use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
 use near_contract_standards::storage_management::StorageBalance;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::U128;
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
+use near_sdk::json_types::{Base58CryptoHash, U128};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    env, ext_contract, log, near_bindgen, serde_json, AccountId, Balance, Gas, Promise,
-    PromiseOrValue, PromiseResult,
+    env, ext_contract, log, near_bindgen, serde_json, AccountId, Balance, BorshStorageKey,
+    CryptoHash, Gas, PanicOnDefault, Promise, PromiseOrValue, PromiseResult,
 };
 
-const CODE: &[u8] = include_bytes!("../../res/amm_wallet.wasm");
+pub use crate::router::*;
+
+mod events;
+mod router;
+
 const N: Balance = 1_000_000_000_000_000_000_000_000;
+/// Number of leading bytes of the pair hash used in a pool's sub-account name.
+const POOL_NAME_HASH_BYTES: usize = 10;
+const POOL_INIT_BALANCE: Balance = 30 * N;
+const STORAGE_DEPOSIT: Balance = N;
+/// Deposit `create_pool` expects: the pool's initial balance plus four storage registrations.
+/// If pool creation fails it is refunded, less the registrations that went through.
+pub const POOL_CREATION_DEPOSIT: Balance = POOL_INIT_BALANCE + 4 * STORAGE_DEPOSIT;
+const INIT_POOL_GAS: Gas = Gas(30_000_000_000_000);
+const STORAGE_DEPOSIT_GAS: Gas = Gas(10_000_000_000_000);
+/// Gas of a pool upgrade besides deploying the code, the pool's `migrate` included.
+const UPGRADE_POOL_GAS: Gas = Gas(30_000_000_000_000);
+/// Gas a pool upgrade needs per byte of code: the pool prepays about 70 Mgas of fees per byte
+/// for deploying it, on top of reading it from its input.
+const UPGRADE_POOL_GAS_PER_BYTE: u64 = 80_000_000;
+const CALLBACK_GAS: Gas = Gas(5_000_000_000_000);
+const POOL_ADMIN_GAS: Gas = Gas(10_000_000_000_000);
+const RECOVER_TOKEN_GAS: Gas = Gas(20_000_000_000_000);
+const EMERGENCY_WITHDRAW_GAS: Gas = Gas(50_000_000_000_000);
+/// Swap fee of new pools in basis points, unless `create_pool` is given one.
+pub const DEFAULT_POOL_FEE: u32 = 30;
+/// Highest swap fee a pool accepts, in basis points.
+pub const MAX_POOL_FEE: u32 = 1_000;
 
 #[ext_contract(ext_ft)]
 pub trait FungibleTokenContract<T = Self>
@@ -46,110 +73,365 @@ where
 
 #[ext_contract(ext_wallet)]
 pub trait AmmWalletContract {
-    fn init(
-        a: AccountId,
-        a_meta: FungibleTokenMetadata,
-        b: AccountId,
-        b_meta: FungibleTokenMetadata,
-    ) -> Self;
+    fn set_fee(&mut self, fee: u32);
+    fn recover_token(&mut self, token: AccountId, receiver_id: AccountId, amount: U128);
+    fn set_paused(&mut self, paused: bool);
+    fn emergency_withdraw(&mut self, receiver_id: AccountId);
+    fn withdraw(&mut self, token: AccountId, amount: Option<U128>, receiver_id: Option<AccountId>);
+}
+
+#[derive(BorshStorageKey, BorshSerialize)]
+enum StorageKey {
+    Pools,
+    PoolKeys,
+    PoolCodes,
+    TokenPools,
+    TokenPoolsInner { token_hash: CryptoHash },
+    RouteRefunds,
+}
+
+/// Token pair in canonical (sorted) order, so `(a, b)` and `(b, a)` map to the same pool.
+pub type PoolKey = (AccountId, AccountId);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PoolStatus {
+    Creating,
+    Ready,
+    Failed { reason: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum UpgradeStatus {
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolUpgrade {
+    pub code_hash: Base58CryptoHash,
+    pub status: UpgradeStatus,
 }
 
-#[near_bindgen]
 #[derive(Clone, Serialize, Deserialize, BorshDeserialize, BorshSerialize)]
 #[serde(crate = "near_sdk::serde")]
+pub struct Pool {
+    pub account_id: AccountId,
+    pub a: AccountId,
+    pub b: AccountId,
+    pub status: PoolStatus,
+    /// Hash of the stored pool code the pool is running.
+    pub code_hash: Base58CryptoHash,
+    /// Outcome of the most recent upgrade of the pool, if it was ever upgraded.
+    pub last_upgrade: Option<PoolUpgrade>,
+    /// Swap fee of the pool in basis points.
+    pub fee: u32,
+    /// Account that paid for the latest creation attempt of the pool, and how much.
+    pub creator: AccountId,
+    pub creation_deposit: U128,
+}
+
+/// Response of `get_factory_info`, versioned like the pools' `get_pool_info`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde", tag = "version")]
+pub enum VersionedFactoryInfo {
+    #[serde(rename = "1")]
+    V1(FactoryInfo),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FactoryInfo {
+    pub owner_id: AccountId,
+    pub pending_owner_id: Option<AccountId>,
+    pub number_of_pools: u64,
+    pub default_pool_code: Option<Base58CryptoHash>,
+    pub paused: bool,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
+    owner_id: AccountId,
+    pending_owner_id: Option<AccountId>,
+    pools: UnorderedMap<PoolKey, Pool>,
+    /// Pool account id to its key in `pools`.
+    pool_keys: LookupMap<AccountId, PoolKey>,
+    /// Token to the keys of every pool it is part of.
+    token_pools: LookupMap<AccountId, UnorderedSet<PoolKey>>,
+    /// `amm_wallet` wasm blobs keyed by their sha256.
+    pool_codes: UnorderedMap<Base58CryptoHash, Vec<u8>>,
+    default_pool_code: Option<Base58CryptoHash>,
+    /// Set by the owner to stop new pools from being created, see `set_paused`.
+    paused: bool,
+    /// Swap route refunds that could not be sent, by account and token, until the account
+    /// takes them with `withdraw_route_refund`.
+    route_refunds: LookupMap<(AccountId, AccountId), Balance>,
+}
+
+/// Layout of `Contract` when it deployed a single pool, read by `migrate`.
+#[derive(BorshDeserialize)]
+struct OldContract {
     wallet: AccountId,
     initialized: bool,
 }
 
-// Define the default, which automatically initializes the contract
-impl Default for Contract {
-    fn default() -> Self {
+#[near_bindgen]
+impl Contract {
+    #[init]
+    pub fn new(owner_id: AccountId) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        Self::internal_new(owner_id)
+    }
+
+    fn internal_new(owner_id: AccountId) -> Self {
         Self {
-            wallet: AccountId::new_unchecked("a".repeat(64)),
-            initialized: false,
+            owner_id,
+            pending_owner_id: None,
+            pools: UnorderedMap::new(StorageKey::Pools),
+            pool_keys: LookupMap::new(StorageKey::PoolKeys),
+            token_pools: LookupMap::new(StorageKey::TokenPools),
+            pool_codes: UnorderedMap::new(StorageKey::PoolCodes),
+            default_pool_code: None,
+            paused: false,
+            route_refunds: LookupMap::new(StorageKey::RouteRefunds),
         }
     }
-}
 
-#[near_bindgen]
-impl Contract {
-    pub fn init(&mut self, a: AccountId, b: AccountId) -> Promise {
-        assert!(!self.initialized, "contract is already initialized");
+    /// Converts the state of a factory deployed before the pool registry, which created a
+    /// single pool, to the current layout, owned by `owner_id`. That pool is imported as ready,
+    /// with `a` and `b` its tokens and `code_hash` the hash of its code as `view_account`
+    /// reports it; they are ignored if the old factory never created a pool. The imported pool
+    /// keeps the full access key the old factory gave it, so it is upgraded with that key
+    /// rather than with `upgrade_pool`, until it runs code with an `upgrade` method.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate(
+        owner_id: AccountId,
+        a: AccountId,
+        b: AccountId,
+        code_hash: Base58CryptoHash,
+    ) -> Self {
+        let state = env::storage_read(b"STATE")
+            .unwrap_or_else(|| env::panic_str("failed to read factory state"));
+        if let Ok(this) = Self::try_from_slice(&state) {
+            return this;
+        }
+        let old = OldContract::try_from_slice(&state)
+            .unwrap_or_else(|_| env::panic_str("failed to read factory state"));
+        let mut this = Self::internal_new(owner_id.clone());
+        if !old.initialized {
+            log!("old factory has no pool to import");
+            return this;
+        }
+        assert_ne!(a, b, "pool tokens must be different");
+        log!("import pool {} for {} and {}", old.wallet, a, b);
+        let key = pool_key(&a, &b);
+        this.pools.insert(
+            &key,
+            &Pool {
+                account_id: old.wallet.clone(),
+                a: a.clone(),
+                b: b.clone(),
+                status: PoolStatus::Ready,
+                code_hash,
+                last_upgrade: None,
+                fee: 0,
+                creator: owner_id,
+                creation_deposit: U128(0),
+            },
+        );
+        this.pool_keys.insert(&old.wallet, &key);
+        this.internal_add_token_pool(&a, &key);
+        this.internal_add_token_pool(&b, &key);
+        this
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner_id.clone()
+    }
+
+    /// First step of an ownership transfer: `new_owner_id` has to call `accept_owner`
+    /// before it becomes the owner.
+    pub fn propose_owner(&mut self, new_owner_id: AccountId) {
+        self.assert_owner();
+        log!("propose owner {}", new_owner_id);
+        self.pending_owner_id = Some(new_owner_id);
+    }
+
+    pub fn accept_owner(&mut self) {
+        let pending_owner_id = self
+            .pending_owner_id
+            .take()
+            .unwrap_or_else(|| env::panic_str("no pending owner"));
+        assert_eq!(
+            env::predecessor_account_id(),
+            pending_owner_id,
+            "only the pending owner can accept ownership"
+        );
+        log!(
+            "owner changed from {} to {}",
+            self.owner_id,
+            pending_owner_id
+        );
+        self.owner_id = pending_owner_id;
+    }
+
+    /// Launches a new `amm_wallet` pool for the `a`/`b` pair and records it in the registry.
+    /// The caller pays for the pool with [`POOL_CREATION_DEPOSIT`]; if any step of the creation
+    /// chain fails the pool is marked as failed and the deposit is refunded, except for the
+    /// storage registrations the tokens kept, so the pool can be created again.
+    /// `fee` is the pool's swap fee in basis points, at most [`MAX_POOL_FEE`] and
+    /// [`DEFAULT_POOL_FEE`] if not given.
+    #[payable]
+    pub fn create_pool(&mut self, a: AccountId, b: AccountId, fee: Option<u32>) -> Promise {
+        assert!(!self.paused, "factory is paused");
+        assert_ne!(a, b, "pool tokens must be different");
+        let fee = fee.unwrap_or(DEFAULT_POOL_FEE);
+        assert_valid_fee(fee);
+        let deposit = env::attached_deposit();
+        assert!(
+            deposit >= POOL_CREATION_DEPOSIT,
+            "attached deposit should be at least {}",
+            POOL_CREATION_DEPOSIT
+        );
+        let code_hash = self
+            .default_pool_code
+            .unwrap_or_else(|| env::panic_str("no pool code is stored"));
+        if let Some(pool) = self.pools.get(&pool_key(&a, &b)) {
+            match pool.status {
+                PoolStatus::Creating => env::panic_str(&format!(
+                    "pool for {} and {} is already being created at {}",
+                    a, b, pool.account_id
+                )),
+                PoolStatus::Ready => env::panic_str(&format!(
+                    "pool for {} and {} already exists at {}",
+                    a, b, pool.account_id
+                )),
+                PoolStatus::Failed { .. } => log!("retry failed pool creation"),
+            }
+        }
+        let account_id = pool_account_id(&a, &b);
+        self.pools.insert(
+            &pool_key(&a, &b),
+            &Pool {
+                account_id: account_id.clone(),
+                a: a.clone(),
+                b: b.clone(),
+                status: PoolStatus::Creating,
+                code_hash,
+                last_upgrade: None,
+                fee,
+                creator: env::predecessor_account_id(),
+                creation_deposit: U128(deposit),
+            },
+        );
+        self.pool_keys.insert(&account_id, &pool_key(&a, &b));
+        self.internal_add_token_pool(&a, &pool_key(&a, &b));
+        self.internal_add_token_pool(&b, &pool_key(&a, &b));
 
-        log!("start init, a: {}, b: {}", a, b);
+        log!("start create pool, a: {}, b: {}", a, b);
         let p_fetch_meta_a = ext_ft::ext(a.clone()).ft_metadata();
         let p_fetch_meta_b = ext_ft::ext(b.clone()).ft_metadata();
         let p_create_wallet = Self::ext(env::current_account_id())
             .with_static_gas(Gas(200_000_000_000_000))
-            .create_wallet_with_metadata(a, b);
-        let p_init_done = Self::ext(env::current_account_id())
-            .with_unused_gas_weight(1)
-            .init_done();
-        p_fetch_meta_a
-            .and(p_fetch_meta_b)
-            .then(p_create_wallet)
-            .then(p_init_done)
+            .create_wallet_with_metadata(a, b, env::predecessor_account_id(), U128(deposit));
+        p_fetch_meta_a.and(p_fetch_meta_b).then(p_create_wallet)
     }
 
     #[private]
-    pub fn create_wallet_with_metadata(&mut self, a: AccountId, b: AccountId) -> Promise {
+    pub fn create_wallet_with_metadata(
+        &mut self,
+        a: AccountId,
+        b: AccountId,
+        caller: AccountId,
+        deposit: U128,
+    ) -> Promise {
         assert_eq!(
             env::promise_results_count(),
             2,
             "should have 2 metadata results"
         );
-        let md_a: FungibleTokenMetadata = match env::promise_result(0) {
-            PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Failed => panic!("Failed to get token metadata"),
-            PromiseResult::Successful(result) => {
-                serde_json::from_slice::<FungibleTokenMetadata>(&result).unwrap()
-            }
-        };
-        let md_b: FungibleTokenMetadata = match env::promise_result(1) {
-            PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Failed => panic!("Failed to get token metadata"),
-            PromiseResult::Successful(result) => {
-                serde_json::from_slice::<FungibleTokenMetadata>(&result).unwrap()
+        let (md_a, md_b) = match (metadata_result(0), metadata_result(1)) {
+            (Some(md_a), Some(md_b)) => (md_a, md_b),
+            _ => {
+                return self.create_pool_failed(
+                    a,
+                    b,
+                    caller,
+                    deposit,
+                    0,
+                    "failed to get token metadata",
+                )
             }
         };
         log!("a: {}, md a: {}", a, serde_json::to_string(&md_a).unwrap());
         log!("b: {}, md b: {}", b, serde_json::to_string(&md_b).unwrap());
-        self.init_wallet(a, md_a, b, md_b)
+
+        let wallet_account_id = pool_account_id(&a, &b);
+        let p_register = self.register_account(wallet_account_id, a.clone(), b.clone());
+        let p_init_wallet = Self::ext(env::current_account_id())
+            .with_unused_gas_weight(1)
+            .init_wallet(a, md_a, b, md_b, caller, deposit);
+        p_register.then(p_init_wallet)
     }
 
-    fn init_wallet(
+    /// Creates, funds, deploys and initializes the pool account in a single batch. If any of
+    /// these actions fails the whole batch is reverted, so no half-built account is left
+    /// behind and the transferred balance comes back to the factory.
+    ///
+    /// The batch never adds an access key, and `create_account` fails on an account that
+    /// already exists, so a pool that is created successfully has no keys at all.
+    #[private]
+    pub fn init_wallet(
         &mut self,
         a: AccountId,
         a_metadata: FungibleTokenMetadata,
         b: AccountId,
         b_metadata: FungibleTokenMetadata,
+        caller: AccountId,
+        deposit: U128,
     ) -> Promise {
-        let wallet_account_id = AccountId::new_unchecked(format!(
-            "{}_{}.{}",
-            "wallet",
-            env::block_height() % 10000,
-            env::current_account_id()
-        ));
+        if !all_results_success() {
+            let spent = STORAGE_DEPOSIT * successful_results_count() as Balance;
+            return self.create_pool_failed(
+                a,
+                b,
+                caller,
+                deposit,
+                spent,
+                "failed to register storage",
+            );
+        }
+        let wallet_account_id = pool_account_id(&a, &b);
         log!("wallet account id: {}", wallet_account_id);
+        let pool = self.pools.get(&pool_key(&a, &b)).expect("pool not found");
 
+        let init_args = serde_json::to_vec(&serde_json::json!({
+            "a": a,
+            "a_meta": a_metadata,
+            "b": b,
+            "b_meta": b_metadata,
+            "fee": pool.fee,
+        }))
+        .unwrap();
         let p_deploy_wallet_contract = Promise::new(wallet_account_id.clone())
             .create_account()
-            .add_full_access_key(env::signer_account_pk())
-            .transfer(30 * N)
-            .deploy_contract(CODE.to_vec());
-        let p_init_wallet_contract = ext_wallet::ext(wallet_account_id.clone())
-            .with_unused_gas_weight(1)
-            .init(a.clone(), a_metadata, b.clone(), b_metadata);
-        let p_register = self.register_account(wallet_account_id.clone(), a, b);
+            .transfer(POOL_INIT_BALANCE)
+            .deploy_contract(self.pool_code(&pool.code_hash))
+            .function_call("init".to_string(), init_args, 0, INIT_POOL_GAS);
         let p_callback = Self::ext(env::current_account_id())
             .with_unused_gas_weight(1)
-            .create_wallet_callback(wallet_account_id);
+            .create_wallet_callback(wallet_account_id, a, b, caller, deposit);
 
-        p_deploy_wallet_contract
-            .then(p_init_wallet_contract)
-            .and(p_register)
-            .then(p_callback)
+        p_deploy_wallet_contract.then(p_callback)
     }
 
     fn register_account(
@@ -159,16 +441,24 @@ impl Contract {
         b: AccountId,
     ) -> Promise {
         let p_register_self_to_a = ext_ft::ext(a.clone())
-            .with_attached_deposit(N)
+            .with_attached_deposit(STORAGE_DEPOSIT)
+            .with_static_gas(STORAGE_DEPOSIT_GAS)
+            .with_unused_gas_weight(0)
             .storage_deposit(None, None);
         let p_register_self_to_b = ext_ft::ext(b.clone())
-            .with_attached_deposit(N)
+            .with_attached_deposit(STORAGE_DEPOSIT)
+            .with_static_gas(STORAGE_DEPOSIT_GAS)
+            .with_unused_gas_weight(0)
             .storage_deposit(None, None);
         let p_register_wallet_to_a = ext_ft::ext(a)
-            .with_attached_deposit(N)
+            .with_attached_deposit(STORAGE_DEPOSIT)
+            .with_static_gas(STORAGE_DEPOSIT_GAS)
+            .with_unused_gas_weight(0)
             .storage_deposit(Some(wallet_account_id.clone()), None);
         let p_register_wallet_to_b = ext_ft::ext(b)
-            .with_attached_deposit(N)
+            .with_attached_deposit(STORAGE_DEPOSIT)
+            .with_static_gas(STORAGE_DEPOSIT_GAS)
+            .with_unused_gas_weight(0)
             .storage_deposit(Some(wallet_account_id), None);
         p_register_self_to_a
             .and(p_register_self_to_b)
@@ -176,45 +466,1155 @@ impl Contract {
             .and(p_register_wallet_to_b)
     }
 
+    /// Every admin method (pool upgrades, pausing, fee changes) goes through this check.
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "only the owner can call this method"
+        );
+    }
+
+    /// Looks up a registered pool by account id, panicking unless it is ready.
+    fn internal_ready_pool(&self, pool_id: &AccountId) -> (PoolKey, Pool) {
+        let key = self
+            .pool_keys
+            .get(pool_id)
+            .unwrap_or_else(|| env::panic_str(&format!("pool {} not found", pool_id)));
+        let pool = self.pools.get(&key).expect("pool not found");
+        assert_eq!(
+            pool.status,
+            PoolStatus::Ready,
+            "pool {} is not ready",
+            pool_id
+        );
+        (key, pool)
+    }
+
+    fn internal_upgrade_pool(
+        &mut self,
+        pool_id: AccountId,
+        code_hash: Base58CryptoHash,
+        code: &[u8],
+    ) -> Promise {
+        let (key, mut pool) = self.internal_ready_pool(&pool_id);
+        pool.last_upgrade = Some(PoolUpgrade {
+            code_hash,
+            status: UpgradeStatus::Pending,
+        });
+        self.pools.insert(&key, &pool);
+
+        let p_upgrade = Promise::new(pool_id.clone()).function_call(
+            "upgrade".to_string(),
+            code.to_vec(),
+            0,
+            upgrade_pool_gas(code),
+        );
+        let p_callback = Self::ext(env::current_account_id())
+            .with_static_gas(CALLBACK_GAS)
+            .with_unused_gas_weight(0)
+            .on_pool_upgraded(pool_id, code_hash);
+        p_upgrade.then(p_callback)
+    }
+
+    fn internal_add_token_pool(&mut self, token: &AccountId, key: &PoolKey) {
+        let mut keys = self.token_pools.get(token).unwrap_or_else(|| {
+            UnorderedSet::new(StorageKey::TokenPoolsInner {
+                token_hash: env::sha256_array(token.as_bytes()),
+            })
+        });
+        keys.insert(key);
+        self.token_pools.insert(token, &keys);
+    }
+
+    fn pool_code(&self, code_hash: &Base58CryptoHash) -> Vec<u8> {
+        self.pool_codes
+            .get(code_hash)
+            .unwrap_or_else(|| env::panic_str("pool code not found"))
+    }
+
+    /// Marks the pool as failed with `reason` and refunds the creation deposit to the caller,
+    /// less the `spent` part that went to storage registrations. Those stay in place, but a
+    /// token does not have to give the deposit back when it is registered again on retry.
+    fn create_pool_failed(
+        &mut self,
+        a: AccountId,
+        b: AccountId,
+        caller: AccountId,
+        deposit: U128,
+        spent: Balance,
+        reason: &str,
+    ) -> Promise {
+        log!("create pool for {} and {} failed: {}", a, b, reason);
+        let key = pool_key(&a, &b);
+        if let Some(mut pool) = self.pools.get(&key) {
+            pool.status = PoolStatus::Failed {
+                reason: reason.to_string(),
+            };
+            self.pools.insert(&key, &pool);
+        }
+        Promise::new(caller).transfer(deposit.0 - spent)
+    }
+
     #[private]
-    pub fn create_wallet_callback(&mut self, wallet_account_id: AccountId) {
+    pub fn create_wallet_callback(
+        &mut self,
+        wallet_account_id: AccountId,
+        a: AccountId,
+        b: AccountId,
+        caller: AccountId,
+        deposit: U128,
+    ) -> PromiseOrValue<()> {
         log!(
             "promise result count in create_wallet_callback: {}",
             env::promise_results_count()
         );
-        assert_all_result_success();
-        log!("create wallet {} done", wallet_account_id);
-        self.wallet = wallet_account_id
+        if !all_results_success() {
+            // Only reached once all four storage registrations went through.
+            return self
+                .create_pool_failed(
+                    a,
+                    b,
+                    caller,
+                    deposit,
+                    4 * STORAGE_DEPOSIT,
+                    "failed to create pool account",
+                )
+                .into();
+        }
+        let key = pool_key(&a, &b);
+        let mut pool = self.pools.get(&key).expect("pool not found");
+        pool.status = PoolStatus::Ready;
+        self.pools.insert(&key, &pool);
+        events::PoolCreated {
+            pool_id: &wallet_account_id,
+            a: &pool.a,
+            b: &pool.b,
+            fee: pool.fee,
+            code_hash: &pool.code_hash,
+        }
+        .emit();
+        let excess = deposit.0.saturating_sub(POOL_CREATION_DEPOSIT);
+        if excess > 0 {
+            log!("refund {} of excess deposit to {}", excess, caller);
+            return Promise::new(caller).transfer(excess).into();
+        }
+        PromiseOrValue::Value(())
+    }
+
+    /// Marks a pool stuck in `Creating` as failed, so its creation can be retried, and refunds
+    /// its creator. Meant for creations whose callback chain broke off before the pool account
+    /// was created, e.g. by running out of gas, which neither finish nor refund the deposit on
+    /// their own. As the registrations may have gone through, they are not refunded.
+    pub fn reset_pool_creation(&mut self, a: AccountId, b: AccountId) -> Promise {
+        self.assert_owner();
+        let key = pool_key(&a, &b);
+        let mut pool = self.pools.get(&key).expect("pool not found");
+        assert_eq!(
+            pool.status,
+            PoolStatus::Creating,
+            "pool is not being created"
+        );
+        log!("reset creation of pool {}", pool.account_id);
+        pool.status = PoolStatus::Failed {
+            reason: "creation reset by the owner".to_string(),
+        };
+        self.pools.insert(&key, &pool);
+        Promise::new(pool.creator).transfer(pool.creation_deposit.0 - 4 * STORAGE_DEPOSIT)
+    }
+
+    /// Address the pool for the `a`/`b` pair is (or will be) deployed at, regardless of the
+    /// order the tokens are given in.
+    pub fn get_pool_account_id(&self, a: AccountId, b: AccountId) -> AccountId {
+        pool_account_id(&a, &b)
+    }
+
+    pub fn get_factory_info(&self) -> VersionedFactoryInfo {
+        VersionedFactoryInfo::V1(FactoryInfo {
+            owner_id: self.owner_id.clone(),
+            pending_owner_id: self.pending_owner_id.clone(),
+            number_of_pools: self.pools.len(),
+            default_pool_code: self.default_pool_code,
+            paused: self.paused,
+        })
+    }
+
+    pub fn get_number_of_pools(&self) -> u64 {
+        self.pools.len()
+    }
+
+    /// Pools in registration order, starting at `from_index` (default 0) and returning at
+    /// most `limit` of them (default all).
+    pub fn get_pools(&self, from_index: Option<u64>, limit: Option<u64>) -> Vec<Pool> {
+        let pools = self.pools.values_as_vector();
+        let from_index = from_index.unwrap_or(0);
+        let to_index = from_index
+            .saturating_add(limit.unwrap_or(pools.len()))
+            .min(pools.len());
+        (from_index..to_index)
+            .filter_map(|index| pools.get(index))
+            .collect()
+    }
+
+    /// The pool for the `a`/`b` pair, regardless of the order the tokens are given in.
+    pub fn get_pool(&self, a: AccountId, b: AccountId) -> Option<Pool> {
+        self.pools.get(&pool_key(&a, &b))
+    }
+
+    /// Pools that trade `token`, paginated like [`Self::get_pools`].
+    pub fn get_pools_for_token(
+        &self,
+        token: AccountId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<Pool> {
+        let keys = match self.token_pools.get(&token) {
+            Some(keys) => keys,
+            None => return vec![],
+        };
+        let keys = keys.as_vector();
+        let from_index = from_index.unwrap_or(0);
+        let to_index = from_index
+            .saturating_add(limit.unwrap_or(keys.len()))
+            .min(keys.len());
+        (from_index..to_index)
+            .filter_map(|index| keys.get(index))
+            .filter_map(|key| self.pools.get(&key))
+            .collect()
+    }
+
+    /// Upgrades a single pool to the stored code `code_hash`, or the default code if not given.
+    pub fn upgrade_pool(
+        &mut self,
+        pool_id: AccountId,
+        code_hash: Option<Base58CryptoHash>,
+    ) -> Promise {
+        self.upgrade_pools(vec![pool_id], code_hash)
+    }
+
+    /// Upgrades every pool in `pool_ids`. Each pool runs its `migrate` right after the new
+    /// code is deployed, and the outcome is recorded in its `last_upgrade`.
+    pub fn upgrade_pools(
+        &mut self,
+        pool_ids: Vec<AccountId>,
+        code_hash: Option<Base58CryptoHash>,
+    ) -> Promise {
+        self.assert_owner();
+        assert!(!pool_ids.is_empty(), "no pools to upgrade");
+        let code_hash = code_hash
+            .or(self.default_pool_code)
+            .unwrap_or_else(|| env::panic_str("no pool code is stored"));
+        let code = self.pool_code(&code_hash);
+        pool_ids
+            .into_iter()
+            .map(|pool_id| self.internal_upgrade_pool(pool_id, code_hash, &code))
+            .reduce(|acc, p| acc.and(p))
+            .unwrap()
+    }
+
+    /// Upgrades the ready pools in `[from_index, from_index + limit)` of the registry. Large
+    /// registries have to be upgraded over several calls to stay within the gas limit: every
+    /// pool takes more gas the larger the code is, about 55 Tgas for 300 KB.
+    pub fn upgrade_all_pools(
+        &mut self,
+        code_hash: Option<Base58CryptoHash>,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Promise {
+        let pool_ids: Vec<AccountId> = self
+            .get_pools(from_index, limit)
+            .into_iter()
+            .filter(|pool| pool.status == PoolStatus::Ready)
+            .map(|pool| pool.account_id)
+            .collect();
+        self.upgrade_pools(pool_ids, code_hash)
     }
 
     #[private]
-    pub fn init_done(&mut self) {
+    pub fn on_pool_upgraded(&mut self, pool_id: AccountId, code_hash: Base58CryptoHash) {
+        let key = self.pool_keys.get(&pool_id).expect("pool not found");
+        let mut pool = self.pools.get(&key).expect("pool not found");
+        let status = if all_results_success() {
+            events::PoolUpgraded {
+                pool_id: &pool_id,
+                code_hash: &code_hash,
+            }
+            .emit();
+            pool.code_hash = code_hash;
+            UpgradeStatus::Succeeded
+        } else {
+            log!(
+                "pool {} failed to upgrade to {}",
+                pool_id,
+                String::from(&code_hash)
+            );
+            UpgradeStatus::Failed
+        };
+        pool.last_upgrade = Some(PoolUpgrade { code_hash, status });
+        self.pools.insert(&key, &pool);
+    }
+
+    /// Changes the swap fee of a pool, in basis points.
+    pub fn set_pool_fee(&mut self, pool_id: AccountId, fee: u32) -> Promise {
+        self.assert_owner();
+        assert_valid_fee(fee);
+        self.internal_ready_pool(&pool_id);
+        ext_wallet::ext(pool_id.clone())
+            .with_static_gas(POOL_ADMIN_GAS)
+            .set_fee(fee)
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(CALLBACK_GAS)
+                    .on_pool_fee_set(pool_id, fee),
+            )
+    }
+
+    #[private]
+    pub fn on_pool_fee_set(&mut self, pool_id: AccountId, fee: u32) {
+        assert!(
+            all_results_success(),
+            "failed to set fee of pool {}",
+            pool_id
+        );
+        let (key, mut pool) = self.internal_ready_pool(&pool_id);
+        pool.fee = fee;
+        self.pools.insert(&key, &pool);
+    }
+
+    /// Sends `amount` of `token` held by a pool to `receiver_id`. Only tokens other than the
+    /// pool's pair can be recovered, like ones sent to it with plain `ft_transfer`.
+    pub fn recover_pool_token(
+        &mut self,
+        pool_id: AccountId,
+        token: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> Promise {
+        self.assert_owner();
+        self.internal_ready_pool(&pool_id);
+        ext_wallet::ext(pool_id)
+            .with_static_gas(RECOVER_TOKEN_GAS)
+            .recover_token(token, receiver_id, amount)
+    }
+
+    /// Sends `amount` (all by default) of the factory's deposit of `token` in a pool to
+    /// `receiver_id`. The factory gets deposits like any other account, for transfers from the
+    /// pool to it that did not arrive.
+    pub fn withdraw_pool_deposit(
+        &mut self,
+        pool_id: AccountId,
+        token: AccountId,
+        receiver_id: AccountId,
+        amount: Option<U128>,
+    ) -> Promise {
+        self.assert_owner();
+        self.internal_ready_pool(&pool_id);
+        ext_wallet::ext(pool_id)
+            .with_attached_deposit(1)
+            .with_static_gas(RECOVER_TOKEN_GAS)
+            .withdraw(token, amount, Some(receiver_id))
+    }
+
+    /// Stops or resumes trading on a pool.
+    pub fn set_pool_paused(&mut self, pool_id: AccountId, paused: bool) -> Promise {
+        self.assert_owner();
+        self.internal_ready_pool(&pool_id);
+        ext_wallet::ext(pool_id)
+            .with_static_gas(POOL_ADMIN_GAS)
+            .set_paused(paused)
+    }
+
+    /// Stops or resumes trading on the ready pools in `[from_index, from_index + limit)` of
+    /// the registry, in batches like `upgrade_all_pools`.
+    pub fn set_all_pools_paused(
+        &mut self,
+        paused: bool,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Promise {
+        self.assert_owner();
+        self.get_pools(from_index, limit)
+            .into_iter()
+            .filter(|pool| pool.status == PoolStatus::Ready)
+            .map(|pool| {
+                ext_wallet::ext(pool.account_id)
+                    .with_static_gas(POOL_ADMIN_GAS)
+                    .set_paused(paused)
+            })
+            .reduce(|acc, p| acc.and(p))
+            .expect("no ready pools in the given range")
+    }
+
+    /// Stops or resumes the creation of new pools. Existing pools are paused on their own
+    /// with `set_pool_paused` or `set_all_pools_paused`.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.assert_owner();
+        self.paused = paused;
+        events::Paused { paused }.emit();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Removes the factory's own liquidity from a paused pool and sends it to `receiver_id`.
+    /// Other LPs keep their shares.
+    pub fn emergency_withdraw_pool(
+        &mut self,
+        pool_id: AccountId,
+        receiver_id: AccountId,
+    ) -> Promise {
+        self.assert_owner();
+        self.internal_ready_pool(&pool_id);
+        ext_wallet::ext(pool_id)
+            .with_static_gas(EMERGENCY_WITHDRAW_GAS)
+            .emergency_withdraw(receiver_id)
+    }
+
+    /// Stores the `amm_wallet` wasm passed as raw input and returns its sha256. The first
+    /// stored code becomes the default one used for new pools.
+    pub fn store_pool_code(&mut self) -> Base58CryptoHash {
+        self.assert_owner();
+        let code = env::input().unwrap_or_else(|| env::panic_str("no code in input"));
+        let code_hash = Base58CryptoHash::from(env::sha256_array(&code));
+        assert!(
+            self.pool_codes.get(&code_hash).is_none(),
+            "pool code already stored"
+        );
+        self.pool_codes.insert(&code_hash, &code);
+        if self.default_pool_code.is_none() {
+            self.default_pool_code = Some(code_hash);
+        }
         log!(
-            "promise result count in init_done: {}",
-            env::promise_results_count()
+            "stored pool code {}, {} bytes",
+            String::from(&code_hash),
+            code.len()
+        );
+        code_hash
+    }
+
+    pub fn set_default_pool_code(&mut self, code_hash: Base58CryptoHash) {
+        self.assert_owner();
+        assert!(
+            self.pool_codes.get(&code_hash).is_some(),
+            "pool code not found"
+        );
+        log!("default pool code set to {}", String::from(&code_hash));
+        self.default_pool_code = Some(code_hash);
+    }
+
+    pub fn remove_pool_code(&mut self, code_hash: Base58CryptoHash) {
+        self.assert_owner();
+        assert_ne!(
+            self.default_pool_code,
+            Some(code_hash),
+            "cannot remove the default pool code"
+        );
+        assert!(
+            self.pool_codes.remove(&code_hash).is_some(),
+            "pool code not found"
         );
-        assert_all_result_success();
-        log!("init done");
-        self.initialized = true
     }
 
-    pub fn state(&mut self) -> Contract {
-        self.clone()
+    pub fn get_pool_code_hashes(&self) -> Vec<Base58CryptoHash> {
+        self.pool_codes.keys().collect()
+    }
+
+    pub fn get_default_pool_code(&self) -> Option<Base58CryptoHash> {
+        self.default_pool_code
     }
+}
+
+fn pool_key(a: &AccountId, b: &AccountId) -> PoolKey {
+    if a < b {
+        (a.clone(), b.clone())
+    } else {
+        (b.clone(), a.clone())
+    }
+}
+
+/// `wallet_<hex>.<factory>`, where `<hex>` is taken from the sha256 of the sorted pair.
+fn pool_account_id(a: &AccountId, b: &AccountId) -> AccountId {
+    let (first, second) = pool_key(a, b);
+    let hash = env::sha256(format!("{}:{}", first, second).as_bytes());
+    let name: String = hash[..POOL_NAME_HASH_BYTES]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("wallet_{}.{}", name, env::current_account_id())
+        .parse()
+        .unwrap_or_else(|_| env::panic_str("pool account id is invalid, factory name is too long"))
+}
+
+/// Gas attached to a pool's `upgrade` with `code`, which grows with the size of the code.
+fn upgrade_pool_gas(code: &[u8]) -> Gas {
+    Gas(UPGRADE_POOL_GAS.0 + UPGRADE_POOL_GAS_PER_BYTE * code.len() as u64)
+}
 
-    pub fn update_wallet_contract(&mut self) -> Promise {
-        Promise::new(self.wallet.clone())
-            .add_full_access_key(env::signer_account_pk())
-            .deploy_contract(CODE.to_vec())
+fn metadata_result(i: u64) -> Option<FungibleTokenMetadata> {
+    match env::promise_result(i) {
+        PromiseResult::NotReady => unreachable!(),
+        PromiseResult::Failed => None,
+        PromiseResult::Successful(result) => {
+            serde_json::from_slice::<FungibleTokenMetadata>(&result).ok()
+        }
     }
 }
 
-fn assert_all_result_success() {
-    for i in 0..env::promise_results_count() {
-        match env::promise_result(i) {
-            PromiseResult::NotReady => unreachable!(),
-            PromiseResult::Failed => panic!("promise result {} failed", i),
-            PromiseResult::Successful(_) => {}
+fn all_results_success() -> bool {
+    (0..env::promise_results_count()).all(|i| match env::promise_result(i) {
+        PromiseResult::NotReady => unreachable!(),
+        PromiseResult::Failed => {
+            log!("promise result {} failed", i);
+            false
+        }
+        PromiseResult::Successful(_) => true,
+    })
+}
+
+fn successful_results_count() -> usize {
+    (0..env::promise_results_count())
+        .filter(|&i| matches!(env::promise_result(i), PromiseResult::Successful(_)))
+        .count()
+}
+
+fn assert_valid_fee(fee: u32) {
+    assert!(
+        fee <= MAX_POOL_FEE,
+        "fee should be at most {} basis points",
+        MAX_POOL_FEE
+    );
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{accounts, get_created_receipts, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig, VMContext};
+
+    use super::*;
+
+    pub(crate) fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn with_input(context: &VMContextBuilder, input: &[u8]) -> VMContext {
+        let mut context = context.build();
+        context.input = input.to_vec();
+        context
+    }
+
+    fn metadata() -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: "ft-1.0.0".to_string(),
+            name: "Token".to_string(),
+            symbol: "TKN".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
+        }
+    }
+
+    /// Amounts of every transfer made to `account_id` so far.
+    fn transfers_to(account_id: &AccountId) -> Vec<Balance> {
+        get_created_receipts()
+            .into_iter()
+            .filter(|receipt| receipt.receiver_id == *account_id)
+            .flat_map(|receipt| receipt.actions)
+            .filter_map(|action| match action {
+                VmAction::Transfer { deposit } => Some(deposit),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub(crate) fn token(name: &str) -> AccountId {
+        format!("{}.near", name).parse().unwrap()
+    }
+
+    /// Factory owned by accounts(1) with pool code stored.
+    pub(crate) fn setup_factory(context: &mut VMContextBuilder) -> Contract {
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let mut contract = Contract::new(accounts(1));
+        testing_env!(with_input(context, b"pool code"));
+        contract.store_pool_code();
+        contract
+    }
+
+    /// Creates the pool of tokens `a` and `b` as accounts(2) and marks it ready.
+    pub(crate) fn ready_pool(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        a: &str,
+        b: &str,
+    ) -> AccountId {
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(POOL_CREATION_DEPOSIT)
+            .build());
+        contract.create_pool(token(a), token(b), None);
+        let pool_id = contract.get_pool_account_id(token(a), token(b));
+        testing_env!(
+            context
+                .predecessor_account_id(accounts(0))
+                .attached_deposit(0)
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        contract.create_wallet_callback(
+            pool_id.clone(),
+            token(a),
+            token(b),
+            accounts(2),
+            POOL_CREATION_DEPOSIT.into(),
+        );
+        pool_id
+    }
+
+    #[test]
+    fn test_transfer_ownership() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.propose_owner(accounts(2));
+        assert_eq!(contract.get_pending_owner(), Some(accounts(2)));
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.accept_owner();
+        assert_eq!(contract.get_owner(), accounts(2));
+        assert_eq!(contract.get_pending_owner(), None);
+    }
+
+    #[test]
+    fn test_factory_info() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let contract = Contract::new(accounts(1));
+        let info = serde_json::to_value(contract.get_factory_info()).unwrap();
+        assert_eq!(info["version"], "1");
+        assert_eq!(info["owner_id"], accounts(1).as_str());
+        assert_eq!(info["number_of_pools"], 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "factory is paused")]
+    fn test_create_pool_while_paused() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.set_paused(true);
+        assert!(contract.is_paused());
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(POOL_CREATION_DEPOSIT)
+            .build());
+        contract.create_pool(accounts(3), accounts(4), None);
+    }
+
+    #[test]
+    fn test_reset_pool_creation() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(POOL_CREATION_DEPOSIT)
+            .build());
+        contract.create_pool(token("a"), token("b"), None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(0)
+            .build());
+        contract.reset_pool_creation(token("b"), token("a"));
+        let pool = contract.get_pool(token("a"), token("b")).unwrap();
+        assert!(matches!(pool.status, PoolStatus::Failed { .. }));
+        assert_eq!(transfers_to(&accounts(2)), vec![POOL_INIT_BALANCE]);
+
+        // The creation can be retried now.
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(POOL_CREATION_DEPOSIT)
+            .build());
+        contract.create_pool(token("a"), token("b"), None);
+        let pool = contract.get_pool(token("a"), token("b")).unwrap();
+        assert_eq!(pool.status, PoolStatus::Creating);
+    }
+
+    #[test]
+    #[should_panic(expected = "pool is not being created")]
+    fn test_reset_ready_pool() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        ready_pool(&mut context, &mut contract, "a", "b");
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.reset_pool_creation(token("a"), token("b"));
+    }
+
+    #[test]
+    fn test_create_pool_refunds_excess_deposit() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        let pool_id = ready_pool(&mut context, &mut contract, "a", "b");
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        contract.create_wallet_callback(
+            pool_id,
+            token("a"),
+            token("b"),
+            accounts(2),
+            (POOL_CREATION_DEPOSIT + 5).into(),
+        );
+        assert_eq!(transfers_to(&accounts(2)), vec![5]);
+    }
+
+    #[test]
+    fn test_create_pool_registration_failed() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        let deposit = POOL_CREATION_DEPOSIT + 5;
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(deposit)
+            .build());
+        contract.create_pool(token("a"), token("b"), None);
+
+        let registered = || PromiseResult::Successful(b"{}".to_vec());
+        testing_env!(
+            context
+                .predecessor_account_id(accounts(0))
+                .attached_deposit(0)
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![
+                registered(),
+                PromiseResult::Failed,
+                registered(),
+                registered()
+            ]
+        );
+        contract.init_wallet(
+            token("a"),
+            metadata(),
+            token("b"),
+            metadata(),
+            accounts(2),
+            deposit.into(),
+        );
+        let pool = contract.get_pool(token("a"), token("b")).unwrap();
+        assert_eq!(
+            pool.status,
+            PoolStatus::Failed {
+                reason: "failed to register storage".to_string()
+            }
+        );
+        // The three registrations that went through are not refunded.
+        assert_eq!(
+            transfers_to(&accounts(2)),
+            vec![deposit - 3 * STORAGE_DEPOSIT]
+        );
+    }
+
+    #[test]
+    fn test_create_pool_account_failed() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(POOL_CREATION_DEPOSIT)
+            .build());
+        contract.create_pool(token("a"), token("b"), None);
+        let pool_id = contract.get_pool_account_id(token("a"), token("b"));
+
+        testing_env!(
+            context
+                .predecessor_account_id(accounts(0))
+                .attached_deposit(0)
+                .build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.create_wallet_callback(
+            pool_id,
+            token("a"),
+            token("b"),
+            accounts(2),
+            POOL_CREATION_DEPOSIT.into(),
+        );
+        let pool = contract.get_pool(token("a"), token("b")).unwrap();
+        assert_eq!(
+            pool.status,
+            PoolStatus::Failed {
+                reason: "failed to create pool account".to_string()
+            }
+        );
+        assert_eq!(transfers_to(&accounts(2)), vec![POOL_INIT_BALANCE]);
+    }
+
+    #[test]
+    #[should_panic(expected = "fee should be at most 1000 basis points")]
+    fn test_create_pool_fee_too_high() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(POOL_CREATION_DEPOSIT)
+            .build());
+        contract.create_pool(token("a"), token("b"), Some(MAX_POOL_FEE + 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "fee should be at most 1000 basis points")]
+    fn test_set_pool_fee_too_high() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        let pool_id = ready_pool(&mut context, &mut contract, "a", "b");
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_pool_fee(pool_id, MAX_POOL_FEE + 1);
+    }
+
+    #[test]
+    fn test_pool_account_id_is_symmetric() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let pool_id = pool_account_id(&token("a"), &token("b"));
+        assert_eq!(pool_id, pool_account_id(&token("b"), &token("a")));
+        assert_ne!(pool_id, pool_account_id(&token("a"), &token("c")));
+        assert!(pool_id
+            .as_str()
+            .ends_with(&format!(".{}", env::current_account_id())));
+    }
+
+    #[test]
+    fn test_get_pools_pagination() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        let ab = ready_pool(&mut context, &mut contract, "a", "b");
+        let ac = ready_pool(&mut context, &mut contract, "a", "c");
+        let bc = ready_pool(&mut context, &mut contract, "b", "c");
+        let ids = |pools: Vec<Pool>| -> Vec<AccountId> {
+            pools.into_iter().map(|pool| pool.account_id).collect()
         };
+
+        assert_eq!(
+            ids(contract.get_pools(None, None)),
+            vec![ab.clone(), ac.clone(), bc.clone()]
+        );
+        assert_eq!(ids(contract.get_pools(Some(1), Some(1))), vec![ac.clone()]);
+        assert_eq!(
+            ids(contract.get_pools(Some(1), Some(u64::MAX))),
+            vec![ac.clone(), bc.clone()]
+        );
+        assert!(contract.get_pools(Some(3), None).is_empty());
+        assert!(contract.get_pools(Some(u64::MAX), Some(10)).is_empty());
+        assert!(contract.get_pools(None, Some(0)).is_empty());
+
+        assert_eq!(
+            ids(contract.get_pools_for_token(token("a"), None, None)),
+            vec![ab.clone(), ac.clone()]
+        );
+        assert_eq!(
+            ids(contract.get_pools_for_token(token("c"), Some(1), Some(10))),
+            vec![bc]
+        );
+        assert_eq!(
+            ids(contract.get_pools_for_token(token("b"), None, Some(1))),
+            vec![ab]
+        );
+        assert!(contract
+            .get_pools_for_token(token("a"), Some(2), None)
+            .is_empty());
+        assert!(contract
+            .get_pools_for_token(token("d"), None, None)
+            .is_empty());
+    }
+
+    /// `(receiver_id, gas, args)` of every call of `method` made so far.
+    fn calls_of(method: &str) -> Vec<(AccountId, Gas, Vec<u8>)> {
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| {
+                let receiver_id = receipt.receiver_id;
+                receipt
+                    .actions
+                    .into_iter()
+                    .filter_map(move |action| match action {
+                        VmAction::FunctionCall {
+                            function_name,
+                            args,
+                            gas,
+                            ..
+                        } if function_name == method => Some((receiver_id.clone(), gas, args)),
+                        _ => None,
+                    })
+            })
+            .collect()
+    }
+
+    fn upgrade_result(
+        context: &VMContextBuilder,
+        contract: &mut Contract,
+        pool_id: &AccountId,
+        code_hash: Base58CryptoHash,
+        result: PromiseResult,
+    ) {
+        testing_env!(
+            context.clone().predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![result]
+        );
+        contract.on_pool_upgraded(pool_id.clone(), code_hash);
+    }
+
+    #[test]
+    fn test_upgrade_pool() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        let pool_id = ready_pool(&mut context, &mut contract, "a", "b");
+        let v1 = contract.get_default_pool_code().unwrap();
+        testing_env!(with_input(
+            context.predecessor_account_id(accounts(1)),
+            b"pool code v2"
+        ));
+        let v2 = contract.store_pool_code();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.upgrade_pool(pool_id.clone(), Some(v2));
+
+        // The gas covers deploying the code on top of the upgrade itself.
+        assert_eq!(
+            calls_of("upgrade"),
+            vec![(
+                pool_id.clone(),
+                Gas(UPGRADE_POOL_GAS.0 + 12 * UPGRADE_POOL_GAS_PER_BYTE),
+                b"pool code v2".to_vec()
+            )]
+        );
+        let pool = contract.get_pool(token("a"), token("b")).unwrap();
+        let upgrade = pool.last_upgrade.unwrap();
+        assert_eq!(upgrade.code_hash, v2);
+        assert_eq!(upgrade.status, UpgradeStatus::Pending);
+        assert_eq!(pool.code_hash, v1);
+
+        upgrade_result(
+            &context,
+            &mut contract,
+            &pool_id,
+            v2,
+            PromiseResult::Successful(vec![]),
+        );
+        let pool = contract.get_pool(token("a"), token("b")).unwrap();
+        assert_eq!(pool.last_upgrade.unwrap().status, UpgradeStatus::Succeeded);
+        assert_eq!(pool.code_hash, v2);
+
+        // A failed upgrade leaves the pool on its code.
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.upgrade_pool(pool_id.clone(), None);
+        upgrade_result(&context, &mut contract, &pool_id, v1, PromiseResult::Failed);
+        let pool = contract.get_pool(token("a"), token("b")).unwrap();
+        let upgrade = pool.last_upgrade.unwrap();
+        assert_eq!(upgrade.code_hash, v1);
+        assert_eq!(upgrade.status, UpgradeStatus::Failed);
+        assert_eq!(pool.code_hash, v2);
+    }
+
+    #[test]
+    fn test_upgrade_all_pools() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        let pool_ab = ready_pool(&mut context, &mut contract, "a", "b");
+        let pool_bc = ready_pool(&mut context, &mut contract, "b", "c");
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(POOL_CREATION_DEPOSIT)
+            .build());
+        contract.create_pool(token("a"), token("c"), None);
+
+        // Pools that are not ready are skipped.
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(0)
+            .build());
+        contract.upgrade_all_pools(None, None, None);
+        let upgraded: Vec<AccountId> = calls_of("upgrade")
+            .into_iter()
+            .map(|(pool_id, _, _)| pool_id)
+            .collect();
+        assert_eq!(upgraded, vec![pool_ab, pool_bc]);
+        for pool in contract.get_pools(None, None) {
+            let status = pool.last_upgrade.map(|upgrade| upgrade.status);
+            if pool.status == PoolStatus::Ready {
+                assert_eq!(status, Some(UpgradeStatus::Pending));
+            } else {
+                assert_eq!(status, None);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "is not ready")]
+    fn test_upgrade_pool_not_ready() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(POOL_CREATION_DEPOSIT)
+            .build());
+        contract.create_pool(token("a"), token("b"), None);
+        let pool_id = contract.get_pool_account_id(token("a"), token("b"));
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(0)
+            .build());
+        contract.upgrade_pools(vec![pool_id], None);
+    }
+
+    #[test]
+    fn test_migrate_from_single_pool_factory() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let code_hash = Base58CryptoHash::from([1; 32]);
+        let old_pool: AccountId = format!("wallet_42.{}", accounts(0)).parse().unwrap();
+        env::storage_write(b"STATE", &(old_pool.clone(), true).try_to_vec().unwrap());
+        let contract = Contract::migrate(accounts(1), token("b"), token("a"), code_hash);
+        assert_eq!(contract.get_owner(), accounts(1));
+        assert_eq!(contract.get_number_of_pools(), 1);
+        let pool = contract.get_pool(token("a"), token("b")).unwrap();
+        assert_eq!(pool.account_id, old_pool);
+        assert_eq!(pool.status, PoolStatus::Ready);
+        assert_eq!(pool.code_hash, code_hash);
+        assert_eq!(
+            contract.get_pools_for_token(token("a"), None, None).len(),
+            1
+        );
+        assert_eq!(contract.internal_ready_pool(&old_pool).1.b, token("a"));
+
+        // Migrating the current layout keeps it as it is.
+        env::state_write(&contract);
+        let contract = Contract::migrate(accounts(2), token("c"), token("d"), code_hash);
+        assert_eq!(contract.get_owner(), accounts(1));
+        assert_eq!(contract.get_number_of_pools(), 1);
+    }
+
+    #[test]
+    fn test_migrate_factory_without_pool() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let unused_pool: AccountId = "a".repeat(64).parse().unwrap();
+        env::storage_write(b"STATE", &(unused_pool, false).try_to_vec().unwrap());
+        let contract = Contract::migrate(
+            accounts(1),
+            token("a"),
+            token("b"),
+            Base58CryptoHash::from([1; 32]),
+        );
+        assert_eq!(contract.get_owner(), accounts(1));
+        assert_eq!(contract.get_number_of_pools(), 0);
+    }
+
+    #[test]
+    fn test_set_all_pools_paused() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        let pool_ab = ready_pool(&mut context, &mut contract, "a", "b");
+        let pool_bc = ready_pool(&mut context, &mut contract, "b", "c");
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .attached_deposit(POOL_CREATION_DEPOSIT)
+            .build());
+        contract.create_pool(token("a"), token("c"), None);
+        let pool_cd = ready_pool(&mut context, &mut contract, "c", "d");
+
+        // The page holds b/c, the pool still being created and c/d.
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .attached_deposit(0)
+            .build());
+        contract.set_all_pools_paused(true, Some(1), Some(3));
+        let calls = calls_of("set_paused");
+        let paused: Vec<AccountId> = calls
+            .iter()
+            .map(|(pool_id, _, _)| pool_id.clone())
+            .collect();
+        assert_eq!(paused, vec![pool_bc, pool_cd]);
+        for (_, _, args) in calls {
+            assert_eq!(args, br#"{"paused":true}"#.to_vec());
+        }
+
+        testing_env!(context.build());
+        contract.set_all_pools_paused(false, None, Some(1));
+        let calls = calls_of("set_paused");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].0, pool_ab);
+        assert_eq!(calls[0].2, br#"{"paused":false}"#.to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "no ready pools in the given range")]
+    fn test_set_all_pools_paused_without_pools() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        ready_pool(&mut context, &mut contract, "a", "b");
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_all_pools_paused(false, Some(1), None);
+    }
+
+    #[test]
+    fn test_store_pool_code() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+
+        testing_env!(with_input(&context, b"code v1"));
+        let v1 = contract.store_pool_code();
+        testing_env!(with_input(&context, b"code v2"));
+        let v2 = contract.store_pool_code();
+        assert_eq!(v1, Base58CryptoHash::from(env::sha256_array(b"code v1")));
+        assert_eq!(contract.get_pool_code_hashes(), vec![v1, v2]);
+        assert_eq!(contract.get_default_pool_code(), Some(v1));
+
+        contract.set_default_pool_code(v2);
+        assert_eq!(contract.get_default_pool_code(), Some(v2));
+        contract.remove_pool_code(v1);
+        assert_eq!(contract.get_pool_code_hashes(), vec![v2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "only the owner can call this method")]
+    fn test_propose_owner_unauthorized() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.propose_owner(accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "only the pending owner can accept ownership")]
+    fn test_accept_owner_unauthorized() {
+        let mut context = get_context(accounts(1));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(1));
+        contract.propose_owner(accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.accept_owner();
     }
 }
@@ -0,0 +1,448 @@
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{
+    assert_one_yocto, env, log, near_bindgen, serde_json, AccountId, Balance, Gas, Promise,
+    PromiseOrValue, PromiseResult,
+};
+
+use crate::*;
+
+/// Every hop takes two `ft_transfer_call`s, each keeping 30 Tgas for the token, plus the
+/// receipts and callbacks of the pool and the factory. Longer routes do not fit into the
+/// 300 Tgas of a transaction.
+const MAX_ROUTE_HOPS: usize = 2;
+/// Gas a hop needs, with some margin, to pay out to the recipient or reach the next hop.
+const ROUTE_HOP_GAS: Gas = Gas(100_000_000_000_000);
+/// Covers a refund of the hop's input and the check of its outcome.
+const ROUTE_CALLBACK_GAS: Gas = Gas(25_000_000_000_000);
+const ROUTE_REFUND_GAS: Gas = Gas(10_000_000_000_000);
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapHop {
+    pub pool_id: AccountId,
+    pub token_out: AccountId,
+}
+
+/// JSON `msg` of an `ft_transfer_call` to the factory, swapping the transferred token through
+/// every pool in `hops`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapRoute {
+    pub hops: Vec<SwapHop>,
+    /// Minimum amount of the last `token_out` the route has to pay out.
+    pub min_amount_out: U128,
+    /// Receives the output, defaults to the sender.
+    pub recipient: Option<AccountId>,
+    /// Receives the input or intermediate token back if a hop fails, defaults to the sender.
+    /// The factory sets it when it forwards the rest of the route through a pool.
+    pub refund_to: Option<AccountId>,
+}
+
+/// `msg` of a swap on `amm_wallet`.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct PoolSwapMsg {
+    min_amount_out: U128,
+    recipient: Option<AccountId>,
+    output_msg: Option<String>,
+    refund_to: Option<AccountId>,
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    /// Runs the first hop of the [`SwapRoute`] in `msg`. The intermediate token of every hop
+    /// comes back here with the rest of the route, until the last pool pays the recipient.
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let token_in = env::predecessor_account_id();
+        log!(
+            "route {} {} from {}, msg: {}",
+            amount.0,
+            token_in,
+            sender_id,
+            msg
+        );
+        let route: SwapRoute =
+            serde_json::from_str(&msg).unwrap_or_else(|_| env::panic_str("invalid swap route"));
+        self.assert_route(&token_in, &route.hops);
+        let gas_left = env::prepaid_gas() - env::used_gas();
+        assert!(
+            gas_left.0 >= ROUTE_HOP_GAS.0 * route.hops.len() as u64,
+            "not enough gas for {} hops",
+            route.hops.len()
+        );
+        let refund_to = route.refund_to.clone().unwrap_or(sender_id);
+        // The factory keeps the tokens, failed hops refund them on their own.
+        self.internal_route_hop(token_in, amount, route, refund_to);
+        PromiseOrValue::Value(U128(0))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Refunds whatever part of the hop's input the pool did not use. If the refund does not
+    /// arrive, e.g. as `refund_to` is not registered with the intermediate token, it is kept
+    /// for `withdraw_route_refund`.
+    #[private]
+    pub fn on_route_hop(
+        &mut self,
+        token_in: AccountId,
+        amount: U128,
+        refund_to: AccountId,
+    ) -> PromiseOrValue<()> {
+        let used = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Failed => 0,
+            PromiseResult::Successful(result) => serde_json::from_slice::<U128>(&result)
+                .map(|used| used.0)
+                .unwrap_or(0),
+        };
+        let refund = amount.0.saturating_sub(used);
+        if refund == 0 {
+            return PromiseOrValue::Value(());
+        }
+        log!(
+            "swap route hop failed, refund {} {} to {}",
+            refund,
+            token_in,
+            refund_to
+        );
+        self.internal_send_route_refund(token_in, refund_to, refund)
+            .into()
+    }
+
+    /// Keeps `amount` of `token` for `account_id` if sending it to them failed.
+    #[private]
+    pub fn on_route_refund(&mut self, token: AccountId, account_id: AccountId, amount: U128) {
+        if all_results_success() {
+            return;
+        }
+        log!(
+            "refund of {} {} to {} failed, keep it for withdraw_route_refund",
+            amount.0,
+            token,
+            account_id
+        );
+        let key = (account_id, token);
+        let refund = self.route_refunds.get(&key).unwrap_or(0);
+        self.route_refunds.insert(&key, &(refund + amount.0));
+    }
+
+    /// Sends the caller's swap route refunds of `token` that could not be sent before. The
+    /// caller has to be registered with `token` by now.
+    #[payable]
+    pub fn withdraw_route_refund(&mut self, token: AccountId) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let refund = self
+            .route_refunds
+            .remove(&(account_id.clone(), token.clone()))
+            .unwrap_or(0);
+        assert!(refund > 0, "nothing to withdraw");
+        self.internal_send_route_refund(token, account_id, refund)
+    }
+
+    /// Swap route refunds of `token` that `account_id` can take with `withdraw_route_refund`.
+    pub fn get_route_refund(&self, account_id: AccountId, token: AccountId) -> U128 {
+        self.route_refunds
+            .get(&(account_id, token))
+            .unwrap_or(0)
+            .into()
+    }
+}
+
+impl Contract {
+    fn internal_send_route_refund(
+        &self,
+        token: AccountId,
+        account_id: AccountId,
+        amount: Balance,
+    ) -> Promise {
+        ext_ft::ext(token.clone())
+            .with_attached_deposit(1)
+            .ft_transfer(
+                account_id.clone(),
+                amount.into(),
+                Some("refund failed swap route".to_string()),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(ROUTE_REFUND_GAS)
+                    .with_unused_gas_weight(0)
+                    .on_route_refund(token, account_id, amount.into()),
+            )
+    }
+
+    fn assert_route(&self, token_in: &AccountId, hops: &[SwapHop]) {
+        assert!(!hops.is_empty(), "swap route has no hops");
+        assert!(
+            hops.len() <= MAX_ROUTE_HOPS,
+            "swap route has more than {} hops",
+            MAX_ROUTE_HOPS
+        );
+        let mut token = token_in.clone();
+        for hop in hops {
+            let (_, pool) = self.internal_ready_pool(&hop.pool_id);
+            assert!(
+                (token == pool.a && hop.token_out == pool.b)
+                    || (token == pool.b && hop.token_out == pool.a),
+                "pool {} does not swap {} to {}",
+                hop.pool_id,
+                token,
+                hop.token_out
+            );
+            token = hop.token_out.clone();
+        }
+    }
+
+    /// Sends `amount` of `token_in` to the first pool of `route`. The last pool pays the
+    /// recipient directly; the others send their output back here with the rest of the route.
+    fn internal_route_hop(
+        &mut self,
+        token_in: AccountId,
+        amount: U128,
+        mut route: SwapRoute,
+        refund_to: AccountId,
+    ) -> Promise {
+        let hop = route.hops.remove(0);
+        let pool_msg = if route.hops.is_empty() {
+            PoolSwapMsg {
+                min_amount_out: route.min_amount_out,
+                recipient: Some(route.recipient.unwrap_or_else(|| refund_to.clone())),
+                output_msg: None,
+                refund_to: None,
+            }
+        } else {
+            // If the output cannot be passed back here, the pool keeps it for `refund_to`.
+            route.refund_to = Some(refund_to.clone());
+            PoolSwapMsg {
+                min_amount_out: U128(0),
+                recipient: None,
+                output_msg: Some(serde_json::to_string(&route).unwrap()),
+                refund_to: Some(refund_to.clone()),
+            }
+        };
+        ext_ft::ext(token_in.clone())
+            .with_attached_deposit(1)
+            .ft_transfer_call(
+                hop.pool_id,
+                amount,
+                Some("swap route hop".to_string()),
+                serde_json::to_string(&pool_msg).unwrap(),
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(ROUTE_CALLBACK_GAS)
+                    .with_unused_gas_weight(0)
+                    .on_route_hop(token_in, amount, refund_to),
+            )
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_sdk::mock::VmAction;
+    use near_sdk::serde_json::json;
+    use near_sdk::test_utils::{accounts, get_created_receipts};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig};
+
+    use super::*;
+    use crate::tests::{get_context, ready_pool, setup_factory, token};
+
+    /// `(receiver_id, method, args)` of every function call made so far.
+    fn function_calls() -> Vec<(AccountId, String, serde_json::Value)> {
+        get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| {
+                let receiver_id = receipt.receiver_id;
+                receipt
+                    .actions
+                    .into_iter()
+                    .filter_map(move |action| match action {
+                        VmAction::FunctionCall {
+                            function_name,
+                            args,
+                            ..
+                        } => Some((
+                            receiver_id.clone(),
+                            function_name,
+                            serde_json::from_slice(&args).unwrap(),
+                        )),
+                        _ => None,
+                    })
+            })
+            .collect()
+    }
+
+    fn route_msg(hops: &[(&AccountId, &AccountId)], min_amount_out: u128) -> String {
+        json!({
+            "hops": hops
+                .iter()
+                .map(|(pool_id, token_out)| json!({"pool_id": pool_id, "token_out": token_out}))
+                .collect::<Vec<_>>(),
+            "min_amount_out": min_amount_out.to_string(),
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_two_hop_route() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        let pool_ab = ready_pool(&mut context, &mut contract, "a", "b");
+        let pool_bc = ready_pool(&mut context, &mut contract, "b", "c");
+
+        // The first hop swaps a for b, paid back here with the rest of the route.
+        testing_env!(context.predecessor_account_id(token("a")).build());
+        let msg = route_msg(&[(&pool_ab, &token("b")), (&pool_bc, &token("c"))], 90);
+        contract.ft_on_transfer(accounts(4), 100.into(), msg);
+        let (receiver_id, method, args) = function_calls().remove(0);
+        assert_eq!(
+            (receiver_id, method.as_str()),
+            (token("a"), "ft_transfer_call")
+        );
+        assert_eq!(args["receiver_id"], pool_ab.as_str());
+        let pool_msg: serde_json::Value =
+            serde_json::from_str(args["msg"].as_str().unwrap()).unwrap();
+        assert_eq!(pool_msg["min_amount_out"], "0");
+        assert_eq!(pool_msg["refund_to"], accounts(4).as_str());
+        let output_msg = pool_msg["output_msg"].as_str().unwrap().to_string();
+
+        // The second hop swaps b for c, paid to the sender.
+        testing_env!(context.predecessor_account_id(token("b")).build());
+        contract.ft_on_transfer(pool_ab, 95.into(), output_msg);
+        let (receiver_id, method, args) = function_calls().remove(0);
+        assert_eq!(
+            (receiver_id, method.as_str()),
+            (token("b"), "ft_transfer_call")
+        );
+        assert_eq!(args["receiver_id"], pool_bc.as_str());
+        assert_eq!(args["amount"], "95");
+        let pool_msg: serde_json::Value =
+            serde_json::from_str(args["msg"].as_str().unwrap()).unwrap();
+        assert_eq!(pool_msg["min_amount_out"], "90");
+        assert_eq!(pool_msg["recipient"], accounts(4).as_str());
+    }
+
+    #[test]
+    fn test_route_hop_refund() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup_factory(&mut context);
+        // A pool returning all of its input, like a swap below min amount out, uses nothing.
+        for (result, refund) in [
+            (PromiseResult::Successful(b"\"0\"".to_vec()), "100"),
+            (PromiseResult::Successful(b"\"60\"".to_vec()), "40"),
+            (PromiseResult::Failed, "100"),
+        ] {
+            testing_env!(
+                context.predecessor_account_id(accounts(0)).build(),
+                VMConfig::test(),
+                RuntimeFeesConfig::test(),
+                Default::default(),
+                vec![result]
+            );
+            contract.on_route_hop(token("a"), 100.into(), accounts(4));
+            let (receiver_id, method, args) = function_calls().remove(0);
+            assert_eq!((receiver_id, method.as_str()), (token("a"), "ft_transfer"));
+            assert_eq!(args["receiver_id"], accounts(4).as_str());
+            assert_eq!(args["amount"], refund);
+            assert_eq!(function_calls()[1].1, "on_route_refund");
+        }
+
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"\"100\"".to_vec())]
+        );
+        contract.on_route_hop(token("a"), 100.into(), accounts(4));
+        assert!(function_calls().is_empty());
+    }
+
+    #[test]
+    fn test_failed_route_refund() {
+        let mut context = get_context(accounts(0));
+        let mut contract = setup_factory(&mut context);
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        contract.on_route_refund(token("b"), accounts(4), 40.into());
+        contract.on_route_refund(token("b"), accounts(4), 60.into());
+        assert_eq!(contract.get_route_refund(accounts(4), token("b")).0, 100);
+        assert_eq!(contract.get_route_refund(accounts(4), token("a")).0, 0);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        contract.withdraw_route_refund(token("b"));
+        assert_eq!(contract.get_route_refund(accounts(4), token("b")).0, 0);
+        let (receiver_id, method, args) = function_calls().remove(0);
+        assert_eq!((receiver_id, method.as_str()), (token("b"), "ft_transfer"));
+        assert_eq!(args["receiver_id"], accounts(4).as_str());
+        assert_eq!(args["amount"], "100");
+    }
+
+    #[test]
+    #[should_panic(expected = "nothing to withdraw")]
+    fn test_withdraw_without_route_refund() {
+        let mut context = get_context(accounts(4));
+        let mut contract = setup_factory(&mut context);
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        contract.withdraw_route_refund(token("b"));
+    }
+
+    #[test]
+    #[should_panic(expected = "swap route has more than 2 hops")]
+    fn test_route_too_long() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        let pool_ab = ready_pool(&mut context, &mut contract, "a", "b");
+        testing_env!(context.predecessor_account_id(token("a")).build());
+        let hops = [
+            (&pool_ab, &token("b")),
+            (&pool_ab, &token("a")),
+            (&pool_ab, &token("b")),
+        ];
+        contract.ft_on_transfer(accounts(4), 100.into(), route_msg(&hops, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "does not swap")]
+    fn test_route_wrong_token() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        let pool_ab = ready_pool(&mut context, &mut contract, "a", "b");
+        testing_env!(context.predecessor_account_id(token("c")).build());
+        let msg = route_msg(&[(&pool_ab, &token("b"))], 0);
+        contract.ft_on_transfer(accounts(4), 100.into(), msg);
+    }
+
+    #[test]
+    #[should_panic(expected = "not enough gas for 2 hops")]
+    fn test_route_not_enough_gas() {
+        let mut context = get_context(accounts(1));
+        let mut contract = setup_factory(&mut context);
+        let pool_ab = ready_pool(&mut context, &mut contract, "a", "b");
+        testing_env!(context
+            .predecessor_account_id(token("a"))
+            .prepaid_gas(Gas(150_000_000_000_000))
+            .build());
+        let msg = route_msg(&[(&pool_ab, &token("b")), (&pool_ab, &token("a"))], 0);
+        contract.ft_on_transfer(accounts(4), 100.into(), msg);
+    }
+}
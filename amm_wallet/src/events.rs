@@ -0,0 +1,208 @@
+//! NEP-297 events of the pool, logged as `EVENT_JSON:` the way the shares' `FtMint` and
+//! `FtBurn` are. Reserves are the pool's balances after the event. Prices are in whole tokens,
+//! normalized by the tokens' decimals like the `get_price` view, and `null` when there is none
+//! or it does not fit into a decimal.
+//!
+//! <https://github.com/near/NEPs/blob/master/specs/Standards/EventsFormat.md>
+
+use near_sdk::json_types::U128;
+use near_sdk::serde::Serialize;
+use near_sdk::{env, serde_json, AccountId};
+
+pub const AMM_STANDARD: &str = "amm";
+pub const AMM_VERSION: &str = "1.0.0";
+
+#[must_use]
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Swap<'a> {
+    pub account_id: &'a AccountId,
+    pub receiver_id: &'a AccountId,
+    pub token_in: &'a AccountId,
+    pub amount_in: U128,
+    pub token_out: &'a AccountId,
+    pub amount_out: U128,
+    /// Part of `amount_in` kept as fee.
+    pub fee: U128,
+    /// Price of one `token_in` in `token_out` the swap got, fee included.
+    pub price: Option<String>,
+    pub a_reserve: U128,
+    pub b_reserve: U128,
+}
+
+#[must_use]
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AddLiquidity<'a> {
+    pub account_id: &'a AccountId,
+    pub a: &'a AccountId,
+    pub b: &'a AccountId,
+    pub amount_a: U128,
+    pub amount_b: U128,
+    pub shares: U128,
+    pub a_reserve: U128,
+    pub b_reserve: U128,
+}
+
+#[must_use]
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RemoveLiquidity<'a> {
+    pub account_id: &'a AccountId,
+    pub a: &'a AccountId,
+    pub b: &'a AccountId,
+    pub amount_a: U128,
+    pub amount_b: U128,
+    pub shares: U128,
+    pub a_reserve: U128,
+    pub b_reserve: U128,
+}
+
+#[must_use]
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeChanged {
+    pub old_fee: u32,
+    pub new_fee: u32,
+}
+
+#[must_use]
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Paused {
+    pub paused: bool,
+}
+
+/// Logged whenever the reserves change.
+#[must_use]
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Sync<'a> {
+    pub a: &'a AccountId,
+    pub b: &'a AccountId,
+    pub a_reserve: U128,
+    pub b_reserve: U128,
+    /// Spot price of one `a` in `b`.
+    pub a_price: Option<String>,
+    /// Spot price of one `b` in `a`.
+    pub b_price: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(
+    crate = "near_sdk::serde",
+    tag = "event",
+    content = "data",
+    rename_all = "snake_case"
+)]
+enum AmmEventKind<'a> {
+    Swap(&'a [Swap<'a>]),
+    AddLiquidity(&'a [AddLiquidity<'a>]),
+    RemoveLiquidity(&'a [RemoveLiquidity<'a>]),
+    FeeChanged(&'a [FeeChanged]),
+    Paused(&'a [Paused]),
+    Sync(&'a [Sync<'a>]),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct AmmEvent<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event_kind: AmmEventKind<'a>,
+}
+
+fn emit(event_kind: AmmEventKind) {
+    let event = AmmEvent {
+        standard: AMM_STANDARD,
+        version: AMM_VERSION,
+        event_kind,
+    };
+    env::log_str(&format!(
+        "EVENT_JSON:{}",
+        serde_json::to_string(&event).unwrap_or_else(|_| env::abort())
+    ));
+}
+
+impl Swap<'_> {
+    pub fn emit(self) {
+        emit(AmmEventKind::Swap(&[self]))
+    }
+}
+
+impl AddLiquidity<'_> {
+    pub fn emit(self) {
+        emit(AmmEventKind::AddLiquidity(&[self]))
+    }
+}
+
+impl RemoveLiquidity<'_> {
+    pub fn emit(self) {
+        emit(AmmEventKind::RemoveLiquidity(&[self]))
+    }
+}
+
+impl FeeChanged {
+    pub fn emit(self) {
+        emit(AmmEventKind::FeeChanged(&[self]))
+    }
+}
+
+impl Paused {
+    pub fn emit(self) {
+        emit(AmmEventKind::Paused(&[self]))
+    }
+}
+
+impl Sync<'_> {
+    pub fn emit(self) {
+        emit(AmmEventKind::Sync(&[self]))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, get_logs};
+
+    #[test]
+    fn test_sync_event() {
+        Sync {
+            a: &accounts(1),
+            b: &accounts(2),
+            a_reserve: 10.into(),
+            b_reserve: 20.into(),
+            a_price: Some("2".to_string()),
+            b_price: None,
+        }
+        .emit();
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"amm","version":"1.0.0","event":"sync","data":[{"a":"bob","b":"charlie","a_reserve":"10","b_reserve":"20","a_price":"2","b_price":null}]}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remove_liquidity_event() {
+        RemoveLiquidity {
+            account_id: &accounts(3),
+            a: &accounts(1),
+            b: &accounts(2),
+            amount_a: 1.into(),
+            amount_b: 2.into(),
+            shares: 3.into(),
+            a_reserve: 10.into(),
+            b_reserve: 20.into(),
+        }
+        .emit();
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"amm","version":"1.0.0","event":"remove_liquidity","data":[{"account_id":"danny","a":"bob","b":"charlie","amount_a":"1","amount_b":"2","shares":"3","a_reserve":"10","b_reserve":"20"}]}"#
+            ]
+        );
+    }
+}
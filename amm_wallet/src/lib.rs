@@ -1,22 +1,99 @@
-use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
+use near_contract_standards::fungible_token::metadata::{
+    FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+};
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::fungible_token::FungibleToken;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::json_types::U128;
+use near_sdk::collections::LookupMap;
+use near_sdk::json_types::{U128, U64};
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    env, ext_contract, log, near_bindgen, AccountId, Balance, Gas, PanicOnDefault, PromiseOrValue,
+    assert_one_yocto, env, ext_contract, log, near_bindgen, serde_json, AccountId, Balance,
+    BorshStorageKey, Gas, PanicOnDefault, Promise, PromiseOrValue, PromiseResult,
 };
 
+pub use crate::oracle::*;
+pub use crate::quote::*;
+
+mod events;
+mod oracle;
+mod quote;
+
 pub const CALLBACK_GAS: Gas = Gas(5_000_000_000_000);
+pub const MIGRATE_GAS: Gas = Gas(10_000_000_000_000);
+/// Swap fees are expressed in basis points of the input amount.
+pub const FEE_DIVISOR: u32 = 10_000;
+pub const MAX_FEE: u32 = 1_000;
 
 #[ext_contract(ext_ft)]
 pub trait FungibleTokenContract {
     fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128>;
 }
 
-#[near_bindgen]
-#[derive(Clone, Serialize, Deserialize, BorshDeserialize, BorshSerialize, PanicOnDefault)]
+/// JSON `msg` of a swap made with `ft_transfer_call`. An empty `msg` swaps without any
+/// bounds, paying the output to the sender.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SwapMsg {
+    /// If the swap would pay out less than this, the whole input is returned instead.
+    pub min_amount_out: U128,
+    /// Receives the output instead of the sender.
+    pub recipient: Option<AccountId>,
+    /// Block timestamp in nanoseconds after which the whole input is returned instead.
+    pub deadline: Option<U64>,
+    /// If set, the output is sent with `ft_transfer_call` using this as its `msg`.
+    pub output_msg: Option<String>,
+    /// Gets the output as a deposit if it does not reach the receiver, instead of the
+    /// receiver. Set by routers whose hops pay back to themselves.
+    pub refund_to: Option<AccountId>,
+}
+
+/// JSON `msg` of a swap paying out exactly `amount_out`. Whatever part of the transferred
+/// amount it does not need is returned.
+#[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
+pub struct ExactOutSwapMsg {
+    pub amount_out: U128,
+    /// If the swap needs more input than this, the whole input is returned instead.
+    pub max_amount_in: U128,
+    /// As in [`SwapMsg`].
+    pub recipient: Option<AccountId>,
+    pub deadline: Option<U64>,
+    pub output_msg: Option<String>,
+    pub refund_to: Option<AccountId>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde", untagged)]
+enum SwapRequest {
+    ExactOut(ExactOutSwapMsg),
+    ExactIn(SwapMsg),
+}
+
+#[derive(BorshSerialize, BorshStorageKey)]
+enum StorageKey {
+    Deposits,
+    Shares,
+    Observations,
+}
+
+/// Tokens the pool holds on behalf of an account, withdrawable with `withdraw`.
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct Deposit {
+    a: Balance,
+    b: Balance,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct AmmWallet {
     a: AccountId,
     a_meta: FungibleTokenMetadata,
@@ -26,8 +103,70 @@ pub struct AmmWallet {
     b_meta: FungibleTokenMetadata,
     b_balance: Balance,
 
-    k: Balance,
+    /// `a_balance * b_balance`, which overflows a u128 for 24-decimal tokens.
+    k: U256,
+
+    owner: AccountId,
+
+    /// Swap fee in basis points, kept in the pool as liquidity.
+    fee: u32,
+    /// Fees collected so far, in a and in b.
+    a_fees: Balance,
+    b_fees: Balance,
+
+    /// Tokens deposited for `add_liquidity` and swap outputs that could not be sent, kept out
+    /// of the balances above.
+    deposits: LookupMap<AccountId, Deposit>,
 
+    /// LP shares of the pool, a NEP-141 token with the pool as its contract.
+    shares: FungibleToken,
+
+    /// Set by the owner to stop trading, see `set_paused`.
+    paused: bool,
+
+    /// Cumulative prices for `get_twap`.
+    oracle: Oracle,
+}
+
+/// Response of `get_pool_info`. New versions are added as variants, so clients can tell
+/// which fields to expect from `version`.
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde", tag = "version")]
+pub enum VersionedPoolInfo {
+    #[serde(rename = "1")]
+    V1(PoolInfo),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolInfo {
+    pub a: AccountId,
+    pub a_decimals: u8,
+    pub a_reserve: U128,
+    pub b: AccountId,
+    pub b_decimals: u8,
+    pub b_reserve: U128,
+    /// Decimal string, as it may not fit into a U128.
+    pub k: String,
+    pub shares_total_supply: U128,
+    pub owner: AccountId,
+    pub fee: u32,
+    pub a_fees: U128,
+    pub b_fees: U128,
+    pub paused: bool,
+}
+
+/// Layout of `AmmWallet` before swap fees, read by `migrate`.
+#[derive(BorshDeserialize)]
+struct OldAmmWallet {
+    a: AccountId,
+    a_meta: FungibleTokenMetadata,
+    a_balance: Balance,
+    b: AccountId,
+    b_meta: FungibleTokenMetadata,
+    b_balance: Balance,
+    #[allow(dead_code)]
+    k: Balance,
     owner: AccountId,
 }
 
@@ -39,38 +178,731 @@ impl AmmWallet {
         a_meta: FungibleTokenMetadata,
         b: AccountId,
         b_meta: FungibleTokenMetadata,
+        fee: u32,
     ) -> Self {
         assert!(!env::state_exists(), "Already initialized");
-        Self {
+        assert_valid_fee(fee);
+        assert!(
+            a_meta.decimals <= MAX_DECIMALS && b_meta.decimals <= MAX_DECIMALS,
+            "token decimals should be at most {}",
+            MAX_DECIMALS
+        );
+        let owner = env::predecessor_account_id();
+        let mut this = Self {
             a,
             a_meta,
             a_balance: 0u128,
             b,
             b_meta,
             b_balance: 0u128,
-            k: 0u128,
-            owner: env::predecessor_account_id(),
-        }
+            k: U256::zero(),
+            owner: owner.clone(),
+            fee,
+            a_fees: 0,
+            b_fees: 0,
+            deposits: LookupMap::new(StorageKey::Deposits),
+            shares: FungibleToken::new(StorageKey::Shares),
+            paused: false,
+            oracle: Oracle::new(StorageKey::Observations),
+        };
+        this.internal_measure_deposit_storage();
+        this.shares.internal_register_account(&owner);
+        this
+    }
+
+    pub fn get_pool_info(&self) -> VersionedPoolInfo {
+        VersionedPoolInfo::V1(PoolInfo {
+            a: self.a.clone(),
+            a_decimals: self.a_meta.decimals,
+            a_reserve: self.a_balance.into(),
+            b: self.b.clone(),
+            b_decimals: self.b_meta.decimals,
+            b_reserve: self.b_balance.into(),
+            k: self.k.to_string(),
+            shares_total_supply: self.shares.total_supply.into(),
+            owner: self.owner.clone(),
+            fee: self.fee,
+            a_fees: self.a_fees.into(),
+            b_fees: self.b_fees.into(),
+            paused: self.paused,
+        })
     }
 
-    pub fn state(&mut self) -> Self {
-        self.clone()
+    /// Redeploys the pool with the wasm passed as raw input and runs `migrate` on the new
+    /// code. Both happen in one batch, so a failed migration leaves the old code in place.
+    /// Pools hold no access keys, so this call from the owner (the factory) is the only way
+    /// to change their code.
+    pub fn upgrade(&self) -> Promise {
+        self.assert_owner();
+        let code = env::input().unwrap_or_else(|| env::panic_str("no code in input"));
+        log!("upgrade pool code, {} bytes", code.len());
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call("migrate".to_string(), vec![], 0, MIGRATE_GAS)
     }
 
+    /// Converts the stored state to the current `AmmWallet` layout. Pools are either already
+    /// on the current layout or still on the original one; the intermediate layouts were never
+    /// deployed. It has to be updated whenever a field of `AmmWallet` changes. Without shares
+    /// yet, the liquidity already in the pool becomes shares of the owner. A reserve without
+    /// the other one cannot be priced into shares, so it becomes a deposit of the owner.
     #[private]
-    pub fn on_transfer_a_back(&mut self, amount: U128) -> PromiseOrValue<U128> {
-        self.a_balance -= Balance::from(amount);
-        PromiseOrValue::Value(U128(0))
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let state = env::storage_read(b"STATE")
+            .unwrap_or_else(|| env::panic_str("failed to read pool state"));
+        if let Ok(this) = Self::try_from_slice(&state) {
+            return this;
+        }
+        let old = OldAmmWallet::try_from_slice(&state)
+            .unwrap_or_else(|_| env::panic_str("failed to read pool state"));
+        let mut this = Self {
+            a: old.a,
+            a_meta: old.a_meta,
+            a_balance: old.a_balance,
+            b: old.b,
+            b_meta: old.b_meta,
+            b_balance: old.b_balance,
+            k: U256::zero(),
+            owner: old.owner,
+            fee: 0,
+            a_fees: 0,
+            b_fees: 0,
+            deposits: LookupMap::new(StorageKey::Deposits),
+            shares: FungibleToken::new(StorageKey::Shares),
+            paused: false,
+            oracle: Oracle::new(StorageKey::Observations),
+        };
+        this.internal_measure_deposit_storage();
+        let owner = this.owner.clone();
+        this.shares.internal_register_account(&owner);
+        if this.a_balance == 0 || this.b_balance == 0 {
+            let (a, b) = (this.a.clone(), this.b.clone());
+            let (amount_a, amount_b) = (this.a_balance, this.b_balance);
+            this.a_balance = 0;
+            this.b_balance = 0;
+            if amount_a > 0 {
+                this.internal_deposit_add(&owner, &a, amount_a);
+            }
+            if amount_b > 0 {
+                this.internal_deposit_add(&owner, &b, amount_b);
+            }
+        }
+        this.internal_update_k();
+        this.internal_mint_shares(&owner, this.k.integer_sqrt().as_u128());
+        this
+    }
+
+    pub fn get_fee(&self) -> u32 {
+        self.fee
+    }
+
+    /// Total swap fees collected in `token`, one of the pool's tokens.
+    pub fn get_fees_collected(&self, token: AccountId) -> U128 {
+        if token == self.a {
+            self.a_fees.into()
+        } else if token == self.b {
+            self.b_fees.into()
+        } else {
+            env::panic_str("token is not in the pool")
+        }
+    }
+
+    /// Amount of `token` the pool holds for `account_id`, not yet added as liquidity.
+    pub fn get_deposit(&self, account_id: AccountId, token: AccountId) -> U128 {
+        let deposit = self.deposits.get(&account_id).unwrap_or_default();
+        if token == self.a {
+            deposit.a.into()
+        } else if token == self.b {
+            deposit.b.into()
+        } else {
+            env::panic_str("token is not in the pool")
+        }
+    }
+
+    /// Sends `amount` of the caller's deposit of `token`, all of it by default, to
+    /// `receiver_id` or else the caller.
+    #[payable]
+    pub fn withdraw(
+        &mut self,
+        token: AccountId,
+        amount: Option<U128>,
+        receiver_id: Option<AccountId>,
+    ) -> Promise {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let deposit = self.get_deposit(account_id.clone(), token.clone()).0;
+        let amount = amount.map(|amount| amount.0).unwrap_or(deposit);
+        assert!(amount > 0, "nothing to withdraw");
+        assert!(amount <= deposit, "not enough deposit to withdraw");
+        self.internal_deposit_sub(&account_id, &token, amount);
+        let receiver_id = receiver_id.unwrap_or_else(|| account_id.clone());
+        log!(
+            "withdraw {} {} of {} to {}",
+            amount,
+            token,
+            account_id,
+            receiver_id
+        );
+        self.internal_transfer_out(
+            token,
+            receiver_id,
+            amount,
+            "withdraw deposit",
+            None,
+            account_id,
+            0,
+        )
+    }
+
+    /// Sends `amount` of `token`, which is not one of the pool's tokens, to `receiver_id`.
+    /// Recovers tokens sent to the pool with plain `ft_transfer`.
+    pub fn recover_token(
+        &mut self,
+        token: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> Promise {
+        self.assert_owner();
+        assert!(
+            token != self.a && token != self.b,
+            "cannot recover a token of the pool"
+        );
+        log!("recover {} {} to {}", amount.0, token, receiver_id);
+        ext_ft::ext(token).with_attached_deposit(1).ft_transfer(
+            receiver_id,
+            amount,
+            Some("recover token".to_string()),
+        )
+    }
+
+    /// Adds liquidity from the caller's deposits, made with `ft_transfer_call` and msg
+    /// `"add_liquidity"`. It takes at most `max_a` and `max_b` at the pool's current ratio and
+    /// leaves the rest as deposit. The first liquidity sets the ratio and is taken in full.
+    /// Returns the shares minted to the caller, which have to be at least `min_shares`.
+    pub fn add_liquidity(&mut self, max_a: U128, max_b: U128, min_shares: U128) -> U128 {
+        assert!(!self.paused, "pool is paused");
+        let account_id = env::predecessor_account_id();
+        assert!(
+            self.shares.accounts.contains_key(&account_id),
+            "register for shares with storage_deposit first"
+        );
+        let (max_a, max_b) = (max_a.0, max_b.0);
+        let deposit = self.deposits.get(&account_id).unwrap_or_default();
+        assert!(
+            max_a <= deposit.a && max_b <= deposit.b,
+            "not enough deposit to add liquidity"
+        );
+        let total_supply = self.shares.total_supply;
+        assert!(
+            total_supply == 0 || (self.a_balance > 0 && self.b_balance > 0),
+            "pool has shares but no liquidity"
+        );
+        let (amount_a, amount_b, shares) = if total_supply == 0 {
+            let shares = (U256::from(max_a) * U256::from(max_b)).integer_sqrt();
+            (max_a, max_b, shares.as_u128())
+        } else {
+            // Amounts are rounded up and shares down, in favour of the pool. The ratios are
+            // compared in U256, as `max_a` worth of b may not fit into a u128.
+            let (amount_a, amount_b) = if U256::from(max_a) * U256::from(self.b_balance)
+                <= U256::from(max_b) * U256::from(self.a_balance)
+            {
+                (max_a, mul_div_ceil(max_a, self.b_balance, self.a_balance))
+            } else {
+                (mul_div_ceil(max_b, self.a_balance, self.b_balance), max_b)
+            };
+            let shares = std::cmp::min(
+                mul_div(amount_a, total_supply, self.a_balance),
+                mul_div(amount_b, total_supply, self.b_balance),
+            );
+            (amount_a, amount_b, shares)
+        };
+        assert!(shares > 0, "not enough liquidity for a share");
+        assert!(
+            shares >= min_shares.0,
+            "shares {} are less than min shares {}",
+            shares,
+            min_shares.0
+        );
+
+        self.internal_update_oracle();
+        let (a, b) = (self.a.clone(), self.b.clone());
+        self.internal_deposit_sub(&account_id, &a, amount_a);
+        self.internal_deposit_sub(&account_id, &b, amount_b);
+        self.a_balance += amount_a;
+        self.b_balance += amount_b;
+        self.internal_grow_k();
+        self.internal_mint_shares(&account_id, shares);
+        events::AddLiquidity {
+            account_id: &account_id,
+            a: &self.a,
+            b: &self.b,
+            amount_a: amount_a.into(),
+            amount_b: amount_b.into(),
+            shares: shares.into(),
+            a_reserve: self.a_balance.into(),
+            b_reserve: self.b_balance.into(),
+        }
+        .emit();
+        shares.into()
+    }
+
+    /// Burns `shares` of the caller and sends them their part of both balances, which has to
+    /// be at least `min_a` and `min_b`. Returns the amounts sent.
+    #[payable]
+    pub fn remove_liquidity(&mut self, shares: U128, min_a: U128, min_b: U128) -> (U128, U128) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let (amount_a, amount_b) = self.internal_remove_liquidity(&account_id, shares.0);
+        assert!(
+            amount_a >= min_a.0 && amount_b >= min_b.0,
+            "amounts {} and {} are less than min amounts {} and {}",
+            amount_a,
+            amount_b,
+            min_a.0,
+            min_b.0
+        );
+        self.internal_transfer_pair_out(account_id, amount_a, amount_b, "remove liquidity");
+        (amount_a.into(), amount_b.into())
+    }
+
+    /// Stops or resumes trading. While paused the pool refunds every incoming transfer and
+    /// takes no liquidity, but LPs can still remove theirs and withdraw deposits.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.assert_owner();
+        self.paused = paused;
+        events::Paused { paused }.emit();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Removes all the liquidity of the owner from the paused pool and sends it to
+    /// `receiver_id`. The shares of every other LP stay redeemable with `remove_liquidity`;
+    /// deposits stay withdrawable.
+    pub fn emergency_withdraw(&mut self, receiver_id: AccountId) {
+        self.assert_owner();
+        assert!(self.paused, "pool is not paused");
+        let owner = self.owner.clone();
+        let shares = self.shares.accounts.get(&owner).unwrap_or(0);
+        assert!(shares > 0, "owner has no shares");
+        let (amount_a, amount_b) = self.internal_remove_liquidity(&owner, shares);
+        log!(
+            "emergency withdraw {} {} and {} {} to {}",
+            amount_a,
+            self.a,
+            amount_b,
+            self.b,
+            receiver_id
+        );
+        self.internal_transfer_pair_out(receiver_id, amount_a, amount_b, "emergency withdraw");
     }
+
+    pub fn set_fee(&mut self, fee: u32) {
+        self.assert_owner();
+        assert_valid_fee(fee);
+        events::FeeChanged {
+            old_fee: self.fee,
+            new_fee: fee,
+        }
+        .emit();
+        self.fee = fee;
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner,
+            "only the owner can call this method"
+        );
+    }
+
+    /// Credits `refund_to` with whatever part of `amount` did not arrive: all of it if the
+    /// transfer failed, the refunded part if it was an `ft_transfer_call`. Accounts without a
+    /// storage registration are credited too, with the pool paying for the deposit's storage,
+    /// as the receiver of a failed transfer is often not registered anywhere. The swap input
+    /// has been used either way, except for the `unused` part returned to the swapper.
     #[private]
-    pub fn on_transfer_b_back(&mut self, amount: U128) -> PromiseOrValue<U128> {
-        self.b_balance -= Balance::from(amount);
-        PromiseOrValue::Value(U128(0))
+    pub fn on_transfer_out(
+        &mut self,
+        token: AccountId,
+        refund_to: AccountId,
+        amount: U128,
+        unused: U128,
+    ) -> U128 {
+        let refunded = match env::promise_result(0) {
+            PromiseResult::NotReady => unreachable!(),
+            PromiseResult::Failed => amount.0,
+            // `ft_transfer` returns nothing, `ft_transfer_call` the amount used by the receiver.
+            PromiseResult::Successful(result) => serde_json::from_slice::<U128>(&result)
+                .map(|used| amount.0.saturating_sub(used.0))
+                .unwrap_or(0),
+        };
+        if refunded > 0 {
+            log!(
+                "{} {} did not arrive, keep it as deposit of {}",
+                refunded,
+                token,
+                refund_to
+            );
+            self.internal_deposit_add(&refund_to, &token, refunded);
+        }
+        unused
+    }
+}
+
+impl AmmWallet {
+    fn internal_swap(
+        &mut self,
+        token_in: AccountId,
+        sender_id: AccountId,
+        amount_in: Balance,
+        swap: SwapMsg,
+    ) -> PromiseOrValue<U128> {
+        if deadline_passed(swap.deadline) {
+            return PromiseOrValue::Value(U128(amount_in));
+        }
+        let (token_out, amount_out, fee_amount) = self.internal_quote_out(&token_in, amount_in);
+        if amount_out == 0 {
+            log!(
+                "amount in {} is too small to pay out, return input",
+                amount_in
+            );
+            return PromiseOrValue::Value(U128(amount_in));
+        }
+        if amount_out < swap.min_amount_out.0 {
+            log!(
+                "amount out {} is less than min amount out {}, return input",
+                amount_out,
+                swap.min_amount_out.0
+            );
+            return PromiseOrValue::Value(U128(amount_in));
+        }
+        let receiver_id = swap.recipient.unwrap_or_else(|| sender_id.clone());
+        let refund_to = swap.refund_to.unwrap_or_else(|| receiver_id.clone());
+        self.internal_settle_swap(
+            sender_id,
+            token_in,
+            amount_in,
+            fee_amount,
+            token_out,
+            amount_out,
+            receiver_id,
+            swap.output_msg,
+            refund_to,
+            0,
+        )
+    }
+
+    /// Swaps the least input of `amount_received` that pays out `swap.amount_out`.
+    fn internal_swap_exact_out(
+        &mut self,
+        token_in: AccountId,
+        sender_id: AccountId,
+        amount_received: Balance,
+        swap: ExactOutSwapMsg,
+    ) -> PromiseOrValue<U128> {
+        if deadline_passed(swap.deadline) {
+            return PromiseOrValue::Value(U128(amount_received));
+        }
+        let amount_out = swap.amount_out.0;
+        let (reserve_in, reserve_out) = self.internal_reserves(&token_in);
+        if amount_out == 0 || amount_out >= reserve_out {
+            log!("cannot pay out {}, return input", amount_out);
+            return PromiseOrValue::Value(U128(amount_received));
+        }
+        let amount_in = swap_in(amount_out, reserve_in, reserve_out, self.k, self.fee);
+        let max_amount_in = std::cmp::min(swap.max_amount_in.0, amount_received);
+        if amount_in > max_amount_in {
+            log!(
+                "amount in {} is more than max amount in {}, return input",
+                amount_in,
+                max_amount_in
+            );
+            return PromiseOrValue::Value(U128(amount_received));
+        }
+        // `amount_in` may buy a little more than `amount_out`, which then stays in the pool.
+        let (token_out, _, fee_amount) = self.internal_quote_out(&token_in, amount_in);
+        let receiver_id = swap.recipient.unwrap_or_else(|| sender_id.clone());
+        let refund_to = swap.refund_to.unwrap_or_else(|| receiver_id.clone());
+        self.internal_settle_swap(
+            sender_id,
+            token_in,
+            amount_in,
+            fee_amount,
+            token_out,
+            amount_out,
+            receiver_id,
+            swap.output_msg,
+            refund_to,
+            amount_received - amount_in,
+        )
+    }
+
+    /// Books a swap of `amount_in` for `amount_out` and sends the output to `receiver_id`.
+    /// The swap resolves to `unused`, the part of the transferred amount to return.
+    #[allow(clippy::too_many_arguments)]
+    fn internal_settle_swap(
+        &mut self,
+        sender_id: AccountId,
+        token_in: AccountId,
+        amount_in: Balance,
+        fee_amount: Balance,
+        token_out: AccountId,
+        amount_out: Balance,
+        receiver_id: AccountId,
+        output_msg: Option<String>,
+        refund_to: AccountId,
+        unused: Balance,
+    ) -> PromiseOrValue<U128> {
+        // The fee part of the input is not priced in but stays in the pool, so `k` grows with
+        // every swap.
+        // The output leaves the books right away, so swaps landing before the transfer has
+        // settled price against the right balances. If it does not arrive, `on_transfer_out`
+        // keeps it for `refund_to` as a deposit.
+        self.internal_update_oracle();
+        if token_in == self.a {
+            self.a_balance += amount_in;
+            self.a_fees += fee_amount;
+            self.b_balance -= amount_out;
+        } else {
+            self.b_balance += amount_in;
+            self.b_fees += fee_amount;
+            self.a_balance -= amount_out;
+        }
+        self.internal_grow_k();
+        events::Swap {
+            account_id: &sender_id,
+            receiver_id: &receiver_id,
+            token_in: &token_in,
+            amount_in: amount_in.into(),
+            token_out: &token_out,
+            amount_out: amount_out.into(),
+            fee: fee_amount.into(),
+            price: self
+                .internal_checked_price(&token_in, amount_in, amount_out)
+                .map(|price| price.to_string()),
+            a_reserve: self.a_balance.into(),
+            b_reserve: self.b_balance.into(),
+        }
+        .emit();
+
+        let memo = if token_out == self.a {
+            "deposit a back to user"
+        } else {
+            "deposit b back to user"
+        };
+        self.internal_transfer_out(
+            token_out,
+            receiver_id,
+            amount_out,
+            memo,
+            output_msg,
+            refund_to,
+            unused,
+        )
+        .into()
+    }
+
+    /// Burns `shares` of `account_id` for their part of both balances, rounded down in favour
+    /// of the remaining LPs, and takes it out of the pool.
+    fn internal_remove_liquidity(
+        &mut self,
+        account_id: &AccountId,
+        shares: Balance,
+    ) -> (Balance, Balance) {
+        assert!(shares > 0, "nothing to remove");
+        let total_supply = self.shares.total_supply;
+        let amount_a = mul_div(self.a_balance, shares, total_supply);
+        let amount_b = mul_div(self.b_balance, shares, total_supply);
+
+        self.internal_update_oracle();
+        self.shares.internal_withdraw(account_id, shares);
+        FtBurn {
+            owner_id: account_id,
+            amount: &shares.into(),
+            memo: Some("liquidity removed"),
+        }
+        .emit();
+        self.a_balance -= amount_a;
+        self.b_balance -= amount_b;
+        self.internal_update_k();
+        events::RemoveLiquidity {
+            account_id,
+            a: &self.a,
+            b: &self.b,
+            amount_a: amount_a.into(),
+            amount_b: amount_b.into(),
+            shares: shares.into(),
+            a_reserve: self.a_balance.into(),
+            b_reserve: self.b_balance.into(),
+        }
+        .emit();
+        (amount_a, amount_b)
+    }
+
+    fn internal_transfer_pair_out(
+        &self,
+        receiver_id: AccountId,
+        amount_a: Balance,
+        amount_b: Balance,
+        memo: &str,
+    ) {
+        if amount_a > 0 {
+            self.internal_transfer_out(
+                self.a.clone(),
+                receiver_id.clone(),
+                amount_a,
+                memo,
+                None,
+                receiver_id.clone(),
+                0,
+            );
+        }
+        if amount_b > 0 {
+            self.internal_transfer_out(
+                self.b.clone(),
+                receiver_id.clone(),
+                amount_b,
+                memo,
+                None,
+                receiver_id,
+                0,
+            );
+        }
+    }
+
+    /// Sends `amount` of `token`, with `ft_transfer_call` if `msg` is set, and checks the
+    /// outcome in `on_transfer_out`, which keeps what did not arrive for `refund_to` and
+    /// resolves to `unused`.
+    #[allow(clippy::too_many_arguments)]
+    fn internal_transfer_out(
+        &self,
+        token: AccountId,
+        receiver_id: AccountId,
+        amount: Balance,
+        memo: &str,
+        msg: Option<String>,
+        refund_to: AccountId,
+        unused: Balance,
+    ) -> Promise {
+        let transfer = match msg {
+            Some(msg) => ext_ft::ext(token.clone())
+                .with_attached_deposit(1)
+                .ft_transfer_call(
+                    receiver_id.clone(),
+                    amount.into(),
+                    Some(memo.to_string()),
+                    msg,
+                ),
+            None => ext_ft::ext(token.clone())
+                .with_attached_deposit(1)
+                .ft_transfer(receiver_id.clone(), amount.into(), Some(memo.to_string())),
+        };
+        transfer.then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(CALLBACK_GAS)
+                .on_transfer_out(token, refund_to, amount.into(), unused.into()),
+        )
+    }
+
+    fn internal_update_k(&mut self) {
+        self.k = U256::from(self.a_balance) * U256::from(self.b_balance);
+        self.internal_emit_sync();
+    }
+
+    /// Updates `k` after a swap or added liquidity, neither of which may lower it.
+    fn internal_grow_k(&mut self) {
+        let k = U256::from(self.a_balance) * U256::from(self.b_balance);
+        assert!(
+            k >= self.k,
+            "invariant violated: a_balance * b_balance {} is less than k {}",
+            k,
+            self.k
+        );
+        self.k = k;
+        self.internal_emit_sync();
+    }
+
+    fn internal_emit_sync(&self) {
+        events::Sync {
+            a: &self.a,
+            b: &self.b,
+            a_reserve: self.a_balance.into(),
+            b_reserve: self.b_balance.into(),
+            a_price: self
+                .internal_checked_price(&self.a, self.a_balance, self.b_balance)
+                .map(|price| price.to_string()),
+            b_price: self
+                .internal_checked_price(&self.b, self.b_balance, self.a_balance)
+                .map(|price| price.to_string()),
+        }
+        .emit();
+    }
+
+    fn internal_mint_shares(&mut self, account_id: &AccountId, amount: Balance) {
+        if amount == 0 {
+            return;
+        }
+        self.shares.internal_deposit(account_id, amount);
+        FtMint {
+            owner_id: account_id,
+            amount: &amount.into(),
+            memo: Some("liquidity added"),
+        }
+        .emit();
+    }
+
+    fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
+        log!("closed @{} with {} shares", account_id, balance);
+    }
+
+    fn on_tokens_burned(&mut self, account_id: AccountId, amount: Balance) {
+        log!("account @{} burned {} shares", account_id, amount);
+    }
+
+    /// Makes the storage registration for shares pay for a deposit entry as well, as only
+    /// registered accounts get deposits.
+    fn internal_measure_deposit_storage(&mut self) {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id = AccountId::new_unchecked("a".repeat(64));
+        self.deposits
+            .insert(&tmp_account_id, &Deposit { a: 0, b: 0 });
+        self.shares.account_storage_usage += env::storage_usage() - initial_storage_usage;
+        self.deposits.remove(&tmp_account_id);
+    }
+
+    fn internal_deposit_add(&mut self, account_id: &AccountId, token: &AccountId, amount: Balance) {
+        let mut deposit = self.deposits.get(account_id).unwrap_or_default();
+        if *token == self.a {
+            deposit.a += amount;
+        } else {
+            deposit.b += amount;
+        }
+        self.deposits.insert(account_id, &deposit);
+    }
+
+    fn internal_deposit_sub(&mut self, account_id: &AccountId, token: &AccountId, amount: Balance) {
+        let mut deposit = self.deposits.get(account_id).unwrap_or_default();
+        if *token == self.a {
+            deposit.a -= amount;
+        } else {
+            deposit.b -= amount;
+        }
+        if deposit.a == 0 && deposit.b == 0 {
+            self.deposits.remove(account_id);
+        } else {
+            self.deposits.insert(account_id, &deposit);
+        }
     }
 }
 
 #[near_bindgen]
 impl FungibleTokenReceiver for AmmWallet {
+    /// The msg `"add_liquidity"` deposits the received token for `add_liquidity`. Anything else
+    /// is a swap of the received token for the other one, configured by `msg` as a JSON
+    /// [`SwapMsg`].
     fn ft_on_transfer(
         &mut self,
         sender_id: AccountId,
@@ -83,57 +915,805 @@ impl FungibleTokenReceiver for AmmWallet {
             amount.0,
             msg
         );
-        if env::predecessor_account_id() == self.a {
-            log!("called by a");
-            self.a_balance += Balance::from(amount);
-            if sender_id != self.owner {
-                log!("receive not from owner");
-                let b_diff = self.b_balance - self.k / (self.a_balance);
-                log!("b_diff: {}", b_diff);
-                if b_diff > 0 {
-                    return ext_ft::ext(self.b.clone())
-                        .with_attached_deposit(1)
-                        .ft_transfer(
-                            sender_id,
-                            b_diff.into(),
-                            Some("deposit b back to user".to_string()),
-                        )
-                        .then(
-                            Self::ext(env::current_account_id()).on_transfer_b_back(b_diff.into()),
-                        )
-                        .into();
-                }
-            } else {
-                log!("receive from owner, update k");
-                self.k = self.a_balance * self.b_balance;
-                log!("k is updated to {}", self.k)
+        let token_in = env::predecessor_account_id();
+        if token_in != self.a && token_in != self.b {
+            log!(
+                "reject {} of unknown token {} from {}",
+                amount.0,
+                token_in,
+                sender_id
+            );
+            return PromiseOrValue::Value(amount);
+        }
+        if self.paused {
+            log!("pool is paused, refund {} {}", amount.0, token_in);
+            return PromiseOrValue::Value(amount);
+        }
+        if msg == "add_liquidity" {
+            if !self.shares.accounts.contains_key(&sender_id) {
+                log!(
+                    "{} is not registered, refund {} {}",
+                    sender_id,
+                    amount.0,
+                    token_in
+                );
+                return PromiseOrValue::Value(amount);
             }
-        } else if env::predecessor_account_id() == self.b {
-            log!("called by b");
-            self.b_balance += Balance::from(amount);
-            if sender_id != self.owner {
-                log!("receive not from owner");
-                let a_diff = self.a_balance - self.k / (self.b_balance);
-                log!("a_diff: {}", a_diff);
-                if a_diff > 0 {
-                    return ext_ft::ext(self.a.clone())
-                        .with_attached_deposit(1)
-                        .ft_transfer(
-                            sender_id,
-                            a_diff.into(),
-                            Some("deposit a back to user".to_string()),
-                        )
-                        .then(
-                            Self::ext(env::current_account_id()).on_transfer_a_back(a_diff.into()),
-                        )
-                        .into();
-                }
-            } else {
-                log!("receive from owner, update k");
-                self.k = self.a_balance * self.b_balance;
-                log!("k is updated to {}", self.k)
+            log!("deposit {} {} to add liquidity", amount.0, token_in);
+            self.internal_deposit_add(&sender_id, &token_in, amount.0);
+            return PromiseOrValue::Value(U128(0));
+        }
+
+        let swap = if msg.is_empty() {
+            SwapRequest::ExactIn(SwapMsg {
+                min_amount_out: U128(0),
+                recipient: None,
+                deadline: None,
+                output_msg: None,
+                refund_to: None,
+            })
+        } else {
+            serde_json::from_str(&msg).unwrap_or_else(|_| env::panic_str("invalid swap msg"))
+        };
+        match swap {
+            SwapRequest::ExactIn(swap) => {
+                self.internal_swap(token_in, sender_id, amount.into(), swap)
             }
+            SwapRequest::ExactOut(swap) => {
+                self.internal_swap_exact_out(token_in, sender_id, amount.into(), swap)
+            }
+        }
+    }
+}
+
+near_contract_standards::impl_fungible_token_core!(AmmWallet, shares, on_tokens_burned);
+near_contract_standards::impl_fungible_token_storage!(AmmWallet, shares, on_account_closed);
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for AmmWallet {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: format!("{}-{} LP shares", self.a_meta.symbol, self.b_meta.symbol),
+            symbol: format!("{}-{}-LP", self.a_meta.symbol, self.b_meta.symbol),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
+        }
+    }
+}
+
+fn deadline_passed(deadline: Option<U64>) -> bool {
+    match deadline {
+        Some(deadline) if env::block_timestamp() > deadline.0 => {
+            log!("swap deadline {} has passed, return input", deadline.0);
+            true
+        }
+        _ => false,
+    }
+}
+
+fn assert_valid_fee(fee: u32) {
+    assert!(
+        fee <= MAX_FEE,
+        "fee should be at most {} basis points",
+        MAX_FEE
+    );
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use near_contract_standards::fungible_token::metadata::FT_METADATA_SPEC;
+    use near_sdk::mock::VmAction;
+    use near_sdk::test_utils::{accounts, get_created_receipts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig};
+
+    use super::*;
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn metadata(symbol: &str) -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: symbol.to_string(),
+            symbol: symbol.to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 24,
+        }
+    }
+
+    /// Pool of accounts(1)/accounts(2) owned by accounts(3) with `a_balance`/`b_balance` of
+    /// liquidity.
+    fn setup_pool(
+        context: &mut VMContextBuilder,
+        fee: u32,
+        a_balance: Balance,
+        b_balance: Balance,
+    ) -> AmmWallet {
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let mut pool = AmmWallet::init(accounts(1), metadata("A"), accounts(2), metadata("B"), fee);
+        deposit(context, &mut pool, accounts(3), a_balance, b_balance);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        pool.add_liquidity(a_balance.into(), b_balance.into(), 0.into());
+        pool
+    }
+
+    fn deposit(
+        context: &mut VMContextBuilder,
+        pool: &mut AmmWallet,
+        account_id: AccountId,
+        a_amount: Balance,
+        b_amount: Balance,
+    ) {
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        pool.ft_on_transfer(
+            account_id.clone(),
+            a_amount.into(),
+            "add_liquidity".to_string(),
+        );
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        pool.ft_on_transfer(account_id, b_amount.into(), "add_liquidity".to_string());
+    }
+
+    fn register(context: &mut VMContextBuilder, pool: &mut AmmWallet, account_id: AccountId) {
+        testing_env!(context
+            .predecessor_account_id(account_id)
+            .attached_deposit(pool.storage_balance_bounds().min.0)
+            .build());
+        pool.storage_deposit(None, None);
+        testing_env!(context.attached_deposit(0).build());
+    }
+
+    #[test]
+    fn test_swap_with_fee() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 100, 1_000_000, 1_000_000);
+        assert_eq!(pool.k, U256::from(1_000_000_000_000u128));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        pool.ft_on_transfer(accounts(4), 10_000.into(), "".to_string());
+        // 1% of the input is kept as fee, the rest is swapped against k.
+        let amount_out = 1_000_000 - 1_000_000_000_000u128.div_ceil(1_000_000 + 9_900);
+        assert_eq!(pool.get_fees_collected(accounts(1)).0, 100);
+        assert_eq!(pool.get_fees_collected(accounts(2)).0, 0);
+        assert_eq!(pool.a_balance, 1_010_000);
+        assert_eq!(pool.b_balance, 1_000_000 - amount_out);
+        assert!(pool.k > U256::from(1_000_000_000_000u128));
+        let swap_event = format!(
+            r#"EVENT_JSON:{{"standard":"amm","version":"1.0.0","event":"swap","data":[{{"account_id":"eugene","receiver_id":"eugene","token_in":"bob","amount_in":"10000","token_out":"charlie","amount_out":"{}","fee":"100","price":"0.9802","a_reserve":"1010000","b_reserve":"{}"}}]}}"#,
+            amount_out,
+            1_000_000 - amount_out
+        );
+        assert!(get_logs().contains(&swap_event));
+    }
+
+    #[test]
+    fn test_quotes_match_swap() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 30, 1_000_000, 2_000_000);
+        assert_eq!(pool.get_spot_price(accounts(1)).0, 2 * 10u128.pow(24));
+        assert_eq!(pool.get_price(accounts(1)), "2");
+        assert_eq!(pool.get_price(accounts(2)), "0.5");
+        let quote = pool.get_return(accounts(1), 10_000.into()).0;
+        let amount_in = pool.get_amount_in(accounts(2), quote.into());
+        assert!(amount_in.0 <= 10_000);
+        assert_eq!(pool.get_return(accounts(1), amount_in).0, quote);
+        assert_eq!(pool.get_price_impact(accounts(1), 10_000.into()), 98);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        pool.ft_on_transfer(accounts(4), 10_000.into(), "".to_string());
+        assert_eq!(pool.b_balance, 2_000_000 - quote);
+    }
+
+    #[test]
+    fn test_swap_exact_out() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 30, 1_000_000, 1_000_000);
+        let amount_in = pool.get_amount_in(accounts(2), 10_000.into()).0;
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let res = pool.ft_on_transfer(
+            accounts(4),
+            20_000.into(),
+            r#"{"amount_out": "10000", "max_amount_in": "15000"}"#.to_string(),
+        );
+        assert!(matches!(res, PromiseOrValue::Promise(_)));
+        assert_eq!(pool.a_balance, 1_000_000 + amount_in);
+        assert_eq!(pool.b_balance, 990_000);
+
+        // The transfer out settles and the rest of the input is returned as unused.
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        let unused = pool.on_transfer_out(
+            accounts(2),
+            accounts(4),
+            10_000.into(),
+            (20_000 - amount_in).into(),
+        );
+        assert_eq!(unused.0, 20_000 - amount_in);
+    }
+
+    #[test]
+    fn test_swap_exact_out_above_max_amount_in() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 30, 1_000_000, 1_000_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let res = pool.ft_on_transfer(
+            accounts(4),
+            20_000.into(),
+            r#"{"amount_out": "10000", "max_amount_in": "10000"}"#.to_string(),
+        );
+        assert!(matches!(res, PromiseOrValue::Value(U128(20_000))));
+        assert_eq!(pool.a_balance, 1_000_000);
+        assert_eq!(pool.b_balance, 1_000_000);
+    }
+
+    #[test]
+    fn test_swap_below_min_amount_out() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 1_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let res = pool.ft_on_transfer(
+            accounts(4),
+            100.into(),
+            r#"{"min_amount_out": "92"}"#.to_string(),
+        );
+        assert!(matches!(res, PromiseOrValue::Value(U128(100))));
+        assert_eq!(pool.a_balance, 1_000);
+        assert_eq!(pool.b_balance, 1_000);
+    }
+
+    #[test]
+    fn test_swap_after_deadline() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 1_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(2_000)
+            .build());
+        let res = pool.ft_on_transfer(
+            accounts(4),
+            100.into(),
+            r#"{"min_amount_out": "0", "deadline": "1000"}"#.to_string(),
+        );
+        assert!(matches!(res, PromiseOrValue::Value(U128(100))));
+        assert_eq!(pool.a_balance, 1_000);
+    }
+
+    #[test]
+    fn test_failed_transfer_out_becomes_deposit() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 1_000);
+        register(&mut context, &mut pool, accounts(4));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        pool.ft_on_transfer(accounts(4), 100.into(), "".to_string());
+        let amount_out = 1_000 - 1_000_000u128.div_ceil(1_100);
+        assert_eq!(pool.b_balance, 1_000 - amount_out);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        pool.on_transfer_out(accounts(2), accounts(4), amount_out.into(), 0.into());
+        assert_eq!(pool.get_deposit(accounts(4), accounts(2)).0, amount_out);
+        assert_eq!(pool.b_balance, 1_000 - amount_out);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        pool.withdraw(accounts(2), None, None);
+        assert_eq!(pool.get_deposit(accounts(4), accounts(2)).0, 0);
+    }
+
+    #[test]
+    fn test_transfer_call_refund_becomes_deposit() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 1_000);
+        register(&mut context, &mut pool, accounts(4));
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"\"30\"".to_vec())]
+        );
+        pool.on_transfer_out(accounts(2), accounts(4), 90.into(), 0.into());
+        assert_eq!(pool.get_deposit(accounts(4), accounts(2)).0, 60);
+
+        testing_env!(
+            context.build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])]
+        );
+        pool.on_transfer_out(accounts(2), accounts(4), 90.into(), 0.into());
+        assert_eq!(pool.get_deposit(accounts(4), accounts(2)).0, 60);
+    }
+
+    #[test]
+    fn test_unregistered_deposits() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 1_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let res = pool.ft_on_transfer(accounts(4), 1.into(), "add_liquidity".to_string());
+        assert!(matches!(res, PromiseOrValue::Value(U128(1))));
+        assert!(pool.deposits.get(&accounts(4)).is_none());
+
+        // A failed output still belongs to the unregistered account, not to the owner.
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            VMConfig::test(),
+            RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![PromiseResult::Failed]
+        );
+        pool.on_transfer_out(accounts(2), accounts(4), 90.into(), 0.into());
+        assert_eq!(pool.get_deposit(accounts(4), accounts(2)).0, 90);
+        assert_eq!(pool.get_deposit(accounts(3), accounts(2)).0, 0);
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        pool.withdraw(accounts(2), None, None);
+        assert_eq!(pool.get_deposit(accounts(4), accounts(2)).0, 0);
+    }
+
+    #[test]
+    fn test_swap_output_refunds_to_refund_to() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 1_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        pool.ft_on_transfer(
+            accounts(4),
+            100.into(),
+            r#"{"min_amount_out": "0", "recipient": "alice", "output_msg": "next", "refund_to": "eugene"}"#
+                .to_string(),
+        );
+        let callback_args = get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| receipt.actions)
+            .find_map(|action| match action {
+                VmAction::FunctionCall {
+                    function_name,
+                    args,
+                    ..
+                } if function_name == "on_transfer_out" => Some(args),
+                _ => None,
+            })
+            .unwrap();
+        let callback_args: serde_json::Value = serde_json::from_slice(&callback_args).unwrap();
+        assert_eq!(callback_args["refund_to"], "eugene");
+    }
+
+    #[test]
+    fn test_add_liquidity_at_pool_ratio() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 4_000);
+        assert_eq!(pool.ft_total_supply().0, 2_000);
+        assert_eq!(pool.ft_balance_of(accounts(3)).0, 2_000);
+
+        deposit(&mut context, &mut pool, accounts(3), 500, 3_000);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let shares = pool.add_liquidity(500.into(), 3_000.into(), 1_000.into());
+        assert_eq!(shares.0, 1_000);
+        assert_eq!(pool.a_balance, 1_500);
+        assert_eq!(pool.b_balance, 6_000);
+        assert_eq!(pool.k, U256::from(9_000_000));
+        // The b over the pool's ratio stays as deposit.
+        assert_eq!(pool.get_deposit(accounts(3), accounts(1)).0, 0);
+        assert_eq!(pool.get_deposit(accounts(3), accounts(2)).0, 1_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "shares 1000 are less than min shares 1001")]
+    fn test_add_liquidity_below_min_shares() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 4_000);
+        deposit(&mut context, &mut pool, accounts(3), 500, 2_000);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        pool.add_liquidity(500.into(), 2_000.into(), 1_001.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "not enough deposit to add liquidity")]
+    fn test_add_liquidity_over_deposit() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 4_000);
+        deposit(&mut context, &mut pool, accounts(3), 500, 1_000);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        pool.add_liquidity(500.into(), 2_000.into(), 0.into());
+    }
+
+    #[test]
+    fn test_transfer_shares() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 4_000);
+        register(&mut context, &mut pool, accounts(4));
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        pool.ft_transfer(accounts(4), 500.into(), None);
+        assert_eq!(pool.ft_balance_of(accounts(3)).0, 1_500);
+        assert_eq!(pool.ft_balance_of(accounts(4)).0, 500);
+    }
+
+    #[test]
+    fn test_remove_liquidity() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 4_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        let (amount_a, amount_b) = pool.remove_liquidity(500.into(), 250.into(), 1_000.into());
+        assert_eq!((amount_a.0, amount_b.0), (250, 1_000));
+        assert_eq!(pool.ft_total_supply().0, 1_500);
+        assert_eq!(pool.ft_balance_of(accounts(3)).0, 1_500);
+        assert_eq!(pool.a_balance, 750);
+        assert_eq!(pool.b_balance, 3_000);
+        assert_eq!(pool.k, U256::from(2_250_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "amounts 250 and 1000 are less than min amounts 251 and 0")]
+    fn test_remove_liquidity_below_min_amounts() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 4_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(1)
+            .build());
+        pool.remove_liquidity(500.into(), 251.into(), 0.into());
+    }
+
+    #[test]
+    fn test_paused_pool_refunds_and_withdraws() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 4_000);
+        pool.set_paused(true);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let res = pool.ft_on_transfer(accounts(4), 100.into(), "".to_string());
+        assert!(matches!(res, PromiseOrValue::Value(U128(100))));
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        pool.emergency_withdraw(accounts(3));
+        assert_eq!(pool.a_balance, 0);
+        assert_eq!(pool.b_balance, 0);
+        assert!(pool.k.is_zero());
+    }
+
+    #[test]
+    fn test_paused_pool_allows_remove_liquidity() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 4_000);
+        pool.set_paused(true);
+        testing_env!(context.attached_deposit(1).build());
+        let (amount_a, amount_b) = pool.remove_liquidity(500.into(), 0.into(), 0.into());
+        assert_eq!((amount_a.0, amount_b.0), (250, 1_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "pool is paused")]
+    fn test_add_liquidity_while_paused() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 4_000);
+        deposit(&mut context, &mut pool, accounts(3), 100, 400);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        pool.set_paused(true);
+        pool.add_liquidity(100.into(), 400.into(), 0.into());
+    }
+
+    #[test]
+    fn test_emergency_withdraw_leaves_other_lps() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 4_000);
+        register(&mut context, &mut pool, accounts(4));
+        deposit(&mut context, &mut pool, accounts(4), 1_000, 4_000);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        pool.add_liquidity(1_000.into(), 4_000.into(), 0.into());
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        pool.set_paused(true);
+        pool.emergency_withdraw(accounts(3));
+        assert_eq!(pool.ft_balance_of(accounts(3)).0, 0);
+        assert_eq!((pool.a_balance, pool.b_balance), (1_000, 4_000));
+
+        testing_env!(context
+            .predecessor_account_id(accounts(4))
+            .attached_deposit(1)
+            .build());
+        let (amount_a, amount_b) = pool.remove_liquidity(2_000.into(), 0.into(), 0.into());
+        assert_eq!((amount_a.0, amount_b.0), (1_000, 4_000));
+    }
+
+    #[test]
+    fn test_add_liquidity_after_emergency_withdraw() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 4_000);
+        pool.set_paused(true);
+        pool.emergency_withdraw(accounts(3));
+        pool.set_paused(false);
+        deposit(&mut context, &mut pool, accounts(3), 500, 500);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let shares = pool.add_liquidity(500.into(), 500.into(), 0.into());
+        assert_eq!(shares.0, 500);
+        assert_eq!(pool.k, U256::from(250_000));
+    }
+
+    #[test]
+    #[should_panic(expected = "pool has shares but no liquidity")]
+    fn test_add_liquidity_without_reserves() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 4_000);
+        pool.a_balance = 0;
+        pool.b_balance = 0;
+        deposit(&mut context, &mut pool, accounts(3), 500, 500);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        pool.add_liquidity(500.into(), 500.into(), 0.into());
+    }
+
+    #[test]
+    #[should_panic(expected = "pool is not paused")]
+    fn test_emergency_withdraw_not_paused() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 4_000);
+        pool.emergency_withdraw(accounts(3));
+    }
+
+    #[test]
+    fn test_twap() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 1_000);
+        // Price of a is 1 for 100 seconds, then 4 after this swap.
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(100_000_000_000)
+            .build());
+        pool.ft_on_transfer(accounts(4), 1_000.into(), "".to_string());
+        assert_eq!(pool.get_price(accounts(1)), "0.25");
+        assert_eq!(pool.get_price(accounts(2)), "4");
+
+        testing_env!(context.block_timestamp(300_000_000_000).build());
+        // Only the observation at 0 is old enough.
+        assert_eq!(pool.get_twap(accounts(2), 300), "3");
+        assert_eq!(pool.get_twap(accounts(1), 300), "0.5");
+        assert_eq!(pool.get_twap(accounts(2), 100), "3");
+    }
+
+    #[test]
+    #[should_panic(expected = "not enough price history for the window")]
+    fn test_twap_without_history() {
+        let mut context = get_context(accounts(3));
+        let pool = setup_pool(&mut context, 0, 1_000, 1_000);
+        pool.get_twap(accounts(1), 60);
+    }
+
+    #[test]
+    fn test_dust_swaps_do_not_drain_pool() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 30, 1_000, 1_000);
+        for i in 0..50 {
+            let token = if i % 2 == 0 { accounts(1) } else { accounts(2) };
+            testing_env!(context.predecessor_account_id(token).build());
+            let k = pool.k;
+            let res = pool.ft_on_transfer(accounts(4), 1.into(), "".to_string());
+            assert!(matches!(res, PromiseOrValue::Value(U128(1))));
+            assert_eq!(pool.k, k);
         }
-        PromiseOrValue::Value(U128(0))
+        // Every 1-token swap would pay out nothing, so its input was returned.
+        assert_eq!(pool.a_balance, 1_000);
+        assert_eq!(pool.b_balance, 1_000);
+        assert_eq!(pool.get_fees_collected(accounts(1)).0, 0);
+    }
+
+    #[test]
+    fn test_very_large_swap() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 30, 10u128.pow(30), 10u128.pow(30));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let amount_in = u128::MAX - 10u128.pow(30);
+        pool.ft_on_transfer(accounts(4), amount_in.into(), "".to_string());
+        assert_eq!(pool.a_balance, u128::MAX);
+        // Almost all of b is paid out but never the last unit.
+        assert!(pool.b_balance >= 1);
+        assert!(pool.k >= U256::exp10(60));
+
+        // The price of b no longer fits into a u128, which does not stop swaps back.
+        let b_balance = pool.b_balance;
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        pool.ft_on_transfer(accounts(4), b_balance.into(), "".to_string());
+        assert_eq!(pool.b_balance, 2 * b_balance);
+        assert!(pool.a_balance < u128::MAX);
+    }
+
+    #[test]
+    fn test_extreme_reserve_ratio() {
+        // One yocto of a is worth more than u128::MAX of b per whole a.
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1, 10u128.pow(15));
+        assert!(pool.internal_wide_spot_price(&accounts(1)) > U256::from(u128::MAX));
+
+        // Not even all of a would be paid out for that much b, so the input is returned.
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(1_000_000_000_000)
+            .build());
+        let res = pool.ft_on_transfer(accounts(3), 10u128.pow(15).into(), "".to_string());
+        assert!(matches!(res, PromiseOrValue::Value(U128(amount)) if amount == 10u128.pow(15)));
+
+        // `max_a` worth of b does not fit into a u128. Adding liquidity accumulates the price
+        // since the pool was set up.
+        deposit(
+            &mut context,
+            &mut pool,
+            accounts(3),
+            10u128.pow(30),
+            10u128.pow(15),
+        );
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let shares = pool.add_liquidity(10u128.pow(30).into(), 10u128.pow(15).into(), 0.into());
+        assert_eq!(shares.0, pool.ft_total_supply().0 / 2);
+        assert_eq!((pool.a_balance, pool.b_balance), (2, 2 * 10u128.pow(15)));
+
+        testing_env!(context.attached_deposit(1).build());
+        let shares = pool.ft_balance_of(accounts(3));
+        pool.remove_liquidity(shares, 0.into(), 0.into());
+        assert_eq!((pool.a_balance, pool.b_balance), (0, 0));
+    }
+
+    #[test]
+    fn test_migrate_from_baseline() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let reserve = 10u128.pow(30);
+        let old = (
+            accounts(1),
+            metadata("A"),
+            reserve,
+            accounts(2),
+            metadata("B"),
+            4 * reserve,
+            0u128,
+            accounts(3),
+        );
+        env::storage_write(b"STATE", &old.try_to_vec().unwrap());
+        let pool = AmmWallet::migrate();
+        assert_eq!(pool.k, U256::from(reserve) * U256::from(4 * reserve));
+        assert_eq!(pool.ft_balance_of(accounts(3)).0, 2 * reserve);
+
+        // Migrating the current layout keeps it as it is.
+        env::state_write(&pool);
+        let pool = AmmWallet::migrate();
+        assert_eq!(pool.b_balance, 4 * reserve);
+        assert_eq!(pool.ft_total_supply().0, 2 * reserve);
+    }
+
+    #[test]
+    fn test_migrate_one_sided_baseline() {
+        let context = get_context(accounts(0));
+        testing_env!(context.build());
+        let old = (
+            accounts(1),
+            metadata("A"),
+            1_000u128,
+            accounts(2),
+            metadata("B"),
+            0u128,
+            0u128,
+            accounts(3),
+        );
+        env::storage_write(b"STATE", &old.try_to_vec().unwrap());
+        let pool = AmmWallet::migrate();
+        assert_eq!((pool.a_balance, pool.b_balance), (0, 0));
+        assert_eq!(pool.ft_total_supply().0, 0);
+        assert_eq!(pool.get_deposit(accounts(3), accounts(1)).0, 1_000);
+        assert_eq!(pool.get_deposit(accounts(3), accounts(2)).0, 0);
+    }
+
+    #[test]
+    fn test_large_reserves() {
+        let mut context = get_context(accounts(3));
+        // 10^12 tokens of 24 decimals on both sides, k is 10^72.
+        let reserve = 10u128.pow(36);
+        let mut pool = setup_pool(&mut context, 30, reserve, reserve);
+        assert_eq!(pool.k, U256::exp10(72));
+        assert_eq!(pool.ft_total_supply().0, reserve);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        let amount_in = 10u128.pow(35);
+        let quote = pool.get_return(accounts(1), amount_in.into()).0;
+        pool.ft_on_transfer(accounts(4), amount_in.into(), "".to_string());
+        assert_eq!(pool.a_balance, reserve + amount_in);
+        assert_eq!(pool.b_balance, reserve - quote);
+        assert!(pool.k > U256::exp10(72));
+    }
+
+    #[test]
+    fn test_reject_unknown_token() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 1_000);
+        testing_env!(context.predecessor_account_id(accounts(5)).build());
+        let res = pool.ft_on_transfer(accounts(4), 100.into(), "".to_string());
+        assert!(matches!(res, PromiseOrValue::Value(U128(100))));
+        assert_eq!(pool.a_balance, 1_000);
+        assert_eq!(pool.b_balance, 1_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot recover a token of the pool")]
+    fn test_recover_pool_token() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 0, 1_000, 1_000);
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        pool.recover_token(accounts(1), accounts(3), 100.into());
+    }
+
+    #[test]
+    fn test_prices_use_decimals() {
+        let context = get_context(accounts(3));
+        testing_env!(context.build());
+        let mut pool = AmmWallet::init(accounts(1), metadata("A"), accounts(2), metadata("B"), 0);
+        pool.b_meta.decimals = 6;
+        // 1000 A against 2500 B.
+        pool.a_balance = 1_000 * 10u128.pow(24);
+        pool.b_balance = 2_500 * 10u128.pow(6);
+        pool.internal_update_k();
+        assert_eq!(pool.get_price(accounts(1)), "2.5");
+        assert_eq!(pool.get_price(accounts(2)), "0.4");
+        assert_eq!(
+            pool.get_execution_price(accounts(1), (10 * 10u128.pow(24)).into()),
+            "2.475247"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "token decimals should be at most 28")]
+    fn test_init_with_too_many_decimals() {
+        let context = get_context(accounts(3));
+        testing_env!(context.build());
+        let mut b_meta = metadata("B");
+        b_meta.decimals = 29;
+        AmmWallet::init(accounts(1), metadata("A"), accounts(2), b_meta, 0);
+    }
+
+    #[test]
+    fn test_pool_info() {
+        let mut context = get_context(accounts(3));
+        let pool = setup_pool(&mut context, 30, 1_000, 4_000);
+        let info = serde_json::to_value(pool.get_pool_info()).unwrap();
+        assert_eq!(info["version"], "1");
+        assert_eq!(info["a_reserve"], "1000");
+        assert_eq!(info["b_reserve"], "4000");
+        assert_eq!(info["k"], "4000000");
+        assert_eq!(info["shares_total_supply"], "2000");
+    }
+
+    #[test]
+    #[should_panic(expected = "only the owner can call this method")]
+    fn test_set_fee_unauthorized() {
+        let mut context = get_context(accounts(3));
+        let mut pool = setup_pool(&mut context, 30, 1_000, 1_000);
+        testing_env!(context.predecessor_account_id(accounts(4)).build());
+        pool.set_fee(50);
     }
 }
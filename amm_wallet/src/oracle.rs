@@ -0,0 +1,154 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::Vector;
+use near_sdk::{env, near_bindgen, AccountId, Balance, IntoStorageKey};
+
+use crate::*;
+
+/// Observations older than `OBSERVATION_PERIOD * OBSERVATION_CAPACITY`, a day, are dropped.
+const OBSERVATION_CAPACITY: u64 = 288;
+/// Seconds between two observations.
+const OBSERVATION_PERIOD: u64 = 300;
+
+/// Cumulative prices at a point in time.
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+pub struct Observation {
+    /// Block timestamp in seconds.
+    pub timestamp: u64,
+    pub a_price_cumulative: U256,
+    pub b_price_cumulative: U256,
+}
+
+/// Sums of the pool's prices over every second since it got liquidity. The price of a token
+/// is the amount of the other token one whole token is worth, as in `get_spot_price`. Prices
+/// are accumulated before reserves change, so a price only counts once a later block sees it.
+/// Sums wrap around on overflow; the difference of two of them is still exact.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Oracle {
+    last: Observation,
+    /// Ring buffer of past cumulative prices, one every `OBSERVATION_PERIOD` at most.
+    observations: Vector<Observation>,
+    next_index: u64,
+}
+
+impl Oracle {
+    pub fn new<S: IntoStorageKey>(prefix: S) -> Self {
+        Self {
+            last: Observation {
+                timestamp: now(),
+                a_price_cumulative: U256::zero(),
+                b_price_cumulative: U256::zero(),
+            },
+            observations: Vector::new(prefix),
+            next_index: 0,
+        }
+    }
+
+    /// Adds the prices since the last update, which held until now.
+    pub fn update(&mut self, a_price: U256, b_price: U256) {
+        self.last = self.cumulative(a_price, b_price);
+        let due = match self.newest_observation() {
+            None => true,
+            Some(newest) => self.last.timestamp >= newest.timestamp + OBSERVATION_PERIOD,
+        };
+        if due {
+            if self.observations.len() < OBSERVATION_CAPACITY {
+                self.observations.push(&self.last);
+            } else {
+                self.observations.replace(self.next_index, &self.last);
+            }
+            self.next_index = (self.next_index + 1) % OBSERVATION_CAPACITY;
+        }
+    }
+
+    /// Cumulative prices now, if the current prices are `a_price` and `b_price`.
+    pub fn cumulative(&self, a_price: U256, b_price: U256) -> Observation {
+        let timestamp = now();
+        let elapsed = U256::from(timestamp - self.last.timestamp);
+        let accumulate = |cumulative: U256, price: U256| {
+            cumulative
+                .overflowing_add(price.overflowing_mul(elapsed).0)
+                .0
+        };
+        Observation {
+            timestamp,
+            a_price_cumulative: accumulate(self.last.a_price_cumulative, a_price),
+            b_price_cumulative: accumulate(self.last.b_price_cumulative, b_price),
+        }
+    }
+
+    /// Newest observation made at or before `timestamp`.
+    pub fn observation_before(&self, timestamp: u64) -> Option<Observation> {
+        let len = self.observations.len();
+        (1..=len)
+            .map(|age| {
+                let index = (self.next_index + len - age) % len;
+                self.observations.get(index).unwrap()
+            })
+            .find(|observation| observation.timestamp <= timestamp)
+    }
+
+    fn newest_observation(&self) -> Option<Observation> {
+        let len = self.observations.len();
+        if len == 0 {
+            return None;
+        }
+        self.observations.get((self.next_index + len - 1) % len)
+    }
+}
+
+fn now() -> u64 {
+    env::block_timestamp() / 1_000_000_000
+}
+
+#[near_bindgen]
+impl AmmWallet {
+    /// Time-weighted average price of one `token` in the other token, in whole tokens like
+    /// `get_price`. It covers at least the last `window_seconds`, from the newest observation
+    /// that old, and at most a day.
+    pub fn get_twap(&self, token: AccountId, window_seconds: u32) -> String {
+        assert!(window_seconds > 0, "window should be positive");
+        self.internal_reserves(&token);
+        let (a_price, b_price) = self.internal_prices();
+        let current = self.oracle.cumulative(a_price, b_price);
+        let start = current
+            .timestamp
+            .checked_sub(window_seconds.into())
+            .and_then(|timestamp| self.oracle.observation_before(timestamp))
+            .expect("not enough price history for the window");
+        let (cumulative, start_cumulative) = if token == self.a {
+            (current.a_price_cumulative, start.a_price_cumulative)
+        } else {
+            (current.b_price_cumulative, start.b_price_cumulative)
+        };
+        let twap = cumulative.overflowing_sub(start_cumulative).0
+            / U256::from(current.timestamp - start.timestamp);
+        let twap: Balance = twap
+            .try_into()
+            .unwrap_or_else(|_| env::panic_str("price is too large"));
+        let other_decimals = if token == self.a {
+            self.b_meta.decimals
+        } else {
+            self.a_meta.decimals
+        };
+        to_decimal(twap, other_decimals).normalize().to_string()
+    }
+}
+
+impl AmmWallet {
+    /// Prices of a and b as accumulated by the oracle, zero without liquidity.
+    pub(crate) fn internal_prices(&self) -> (U256, U256) {
+        if self.a_balance == 0 || self.b_balance == 0 {
+            return (U256::zero(), U256::zero());
+        }
+        (
+            self.internal_wide_spot_price(&self.a),
+            self.internal_wide_spot_price(&self.b),
+        )
+    }
+
+    /// Has to be called before every change of the reserves.
+    pub(crate) fn internal_update_oracle(&mut self) {
+        let (a_price, b_price) = self.internal_prices();
+        self.oracle.update(a_price, b_price);
+    }
+}
@@ -0,0 +1,301 @@
+use near_sdk::json_types::U128;
+use near_sdk::{env, near_bindgen, AccountId, Balance};
+use rust_decimal::Decimal;
+
+use crate::*;
+
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+
+pub use u256::U256;
+
+// The code generated by `construct_uint!` does not pass clippy.
+#[allow(clippy::all)]
+mod u256 {
+    uint::construct_uint! {
+        /// Wide enough for the product of two balances.
+        pub struct U256(4);
+    }
+}
+
+impl BorshSerialize for U256 {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        BorshSerialize::serialize(&self.0, writer)
+    }
+}
+
+impl BorshDeserialize for U256 {
+    fn deserialize(buf: &mut &[u8]) -> std::io::Result<Self> {
+        Ok(Self(<[u64; 4] as BorshDeserialize>::deserialize(buf)?))
+    }
+}
+
+/// Most token decimals a price can be computed for, the largest scale of a [`Decimal`].
+pub const MAX_DECIMALS: u8 = 28;
+/// Largest integer a [`Decimal`] can hold, 96 bits.
+const MAX_DECIMAL_MANTISSA: Balance = (1 << 96) - 1;
+
+/// `amount` of a token with `decimals` as a [`Decimal`], dropping the lowest digits that do
+/// not fit into it.
+pub(crate) fn to_decimal(amount: Balance, decimals: u8) -> Decimal {
+    try_to_decimal(amount, decimals).unwrap_or_else(|| env::panic_str("price is too large"))
+}
+
+/// Like [`to_decimal`], but `None` if the whole part does not fit into a [`Decimal`].
+pub(crate) fn try_to_decimal(mut amount: Balance, mut decimals: u8) -> Option<Decimal> {
+    while amount > MAX_DECIMAL_MANTISSA && decimals > 0 {
+        amount /= 10;
+        decimals -= 1;
+    }
+    Decimal::try_from_i128_with_scale(amount as i128, decimals.into()).ok()
+}
+
+/// `a * b / c` rounded down, without overflowing on the product.
+pub(crate) fn mul_div(a: Balance, b: Balance, c: Balance) -> Balance {
+    (U256::from(a) * U256::from(b) / U256::from(c)).as_u128()
+}
+
+/// `a * b / c` rounded up, without overflowing on the product.
+pub(crate) fn mul_div_ceil(a: Balance, b: Balance, c: Balance) -> Balance {
+    ceil_div(U256::from(a) * U256::from(b), U256::from(c)).as_u128()
+}
+
+fn ceil_div(a: U256, b: U256) -> U256 {
+    let (quotient, remainder) = a.div_mod(b);
+    if remainder.is_zero() {
+        quotient
+    } else {
+        quotient + 1
+    }
+}
+
+/// Output of swapping `amount_in` against `reserve_in`/`reserve_out` with invariant `k`, and
+/// the fee taken from the input. This is what a swap pays out. Both are rounded against the
+/// trader, so the reserves after the swap never multiply to less than `k`.
+pub(crate) fn swap_out(
+    amount_in: Balance,
+    reserve_in: Balance,
+    reserve_out: Balance,
+    k: U256,
+    fee: u32,
+) -> (Balance, Balance) {
+    let fee_amount = mul_div_ceil(amount_in, fee.into(), FEE_DIVISOR.into());
+    let reserve_in_after = U256::from(reserve_in) + U256::from(amount_in - fee_amount);
+    let reserve_out_after = ceil_div(k, reserve_in_after).as_u128();
+    let amount_out = reserve_out.saturating_sub(reserve_out_after);
+    (amount_out, fee_amount)
+}
+
+/// Smallest input for which [`swap_out`] pays at least `amount_out`.
+pub(crate) fn swap_in(
+    amount_out: Balance,
+    reserve_in: Balance,
+    reserve_out: Balance,
+    k: U256,
+    fee: u32,
+) -> Balance {
+    assert!(amount_out < reserve_out, "not enough liquidity");
+    // `k / (reserve_in + x)` rounded up has to drop to `reserve_out - amount_out` at most.
+    let in_after_fee =
+        ceil_div(k, U256::from(reserve_out - amount_out)).saturating_sub(U256::from(reserve_in));
+    // The fee is rounded up, so `amount_in - fee` is `amount_in * (divisor - fee) / divisor`
+    // rounded down.
+    let divisor = U256::from(FEE_DIVISOR);
+    let amount_in = ceil_div(in_after_fee * divisor, divisor - U256::from(fee));
+    amount_in
+        .try_into()
+        .unwrap_or_else(|_| env::panic_str("amount in is too large"))
+}
+
+#[near_bindgen]
+impl AmmWallet {
+    /// Amount of the other token a swap of `amount_in` of `token_in` pays out right now.
+    pub fn get_return(&self, token_in: AccountId, amount_in: U128) -> U128 {
+        self.internal_quote_out(&token_in, amount_in.0).1.into()
+    }
+
+    /// Amount of the other token a swap needs to pay out `amount_out` of `token_out`.
+    pub fn get_amount_in(&self, token_out: AccountId, amount_out: U128) -> U128 {
+        let (reserve_out, reserve_in) = self.internal_reserves(&token_out);
+        swap_in(amount_out.0, reserve_in, reserve_out, self.k, self.fee).into()
+    }
+
+    /// Amount of the other token one whole `token` (`10^decimals`) is worth at the current
+    /// reserves, without fee and price impact.
+    pub fn get_spot_price(&self, token: AccountId) -> U128 {
+        self.internal_spot_price(&token).into()
+    }
+
+    /// Price of one `token` in the other token at the current reserves, both in whole tokens
+    /// as set by their `decimals`. For example `"2.5"`.
+    pub fn get_price(&self, token: AccountId) -> String {
+        let (reserve, other_reserve) = self.internal_reserves(&token);
+        assert!(reserve > 0, "pool has no liquidity");
+        self.internal_price(&token, reserve, other_reserve)
+            .to_string()
+    }
+
+    /// Average price of one `token_in` in the other token a swap of `amount_in` gets, fee
+    /// included, in whole tokens like [`Self::get_price`].
+    pub fn get_execution_price(&self, token_in: AccountId, amount_in: U128) -> String {
+        assert!(amount_in.0 > 0, "amount in should be positive");
+        let (_, amount_out, _) = self.internal_quote_out(&token_in, amount_in.0);
+        self.internal_price(&token_in, amount_in.0, amount_out)
+            .to_string()
+    }
+
+    /// How much worse than the spot price a swap of `amount_in` of `token_in` is, in basis
+    /// points. The fee is not part of it.
+    pub fn get_price_impact(&self, token_in: AccountId, amount_in: U128) -> u32 {
+        let (reserve_in, reserve_out) = self.internal_reserves(&token_in);
+        let (_, amount_out, fee_amount) = self.internal_quote_out(&token_in, amount_in.0);
+        let spot_out = mul_div(amount_in.0 - fee_amount, reserve_out, reserve_in);
+        if spot_out == 0 {
+            return 0;
+        }
+        let impact = mul_div(
+            spot_out.saturating_sub(amount_out),
+            FEE_DIVISOR.into(),
+            spot_out,
+        );
+        impact as u32
+    }
+}
+
+impl AmmWallet {
+    /// Reserves of `token` and of the other token.
+    pub(crate) fn internal_reserves(&self, token: &AccountId) -> (Balance, Balance) {
+        if *token == self.a {
+            (self.a_balance, self.b_balance)
+        } else if *token == self.b {
+            (self.b_balance, self.a_balance)
+        } else {
+            env::panic_str("token is not in the pool")
+        }
+    }
+
+    pub(crate) fn internal_spot_price(&self, token: &AccountId) -> Balance {
+        self.internal_wide_spot_price(token)
+            .try_into()
+            .unwrap_or_else(|_| env::panic_str("price is too large"))
+    }
+
+    /// Like [`Self::internal_spot_price`], but without the u128 limit that extreme reserve
+    /// ratios exceed.
+    pub(crate) fn internal_wide_spot_price(&self, token: &AccountId) -> U256 {
+        let (reserve, other_reserve) = self.internal_reserves(token);
+        assert!(reserve > 0, "pool has no liquidity");
+        let decimals = self.internal_decimals(token);
+        U256::from(other_reserve) * U256::exp10(decimals.into()) / U256::from(reserve)
+    }
+
+    pub(crate) fn internal_decimals(&self, token: &AccountId) -> u8 {
+        if *token == self.a {
+            self.a_meta.decimals
+        } else {
+            self.b_meta.decimals
+        }
+    }
+
+    /// Price of one whole `token_in` in whole units of the other token, if `amount_in` of it
+    /// is worth `amount_out`.
+    pub(crate) fn internal_price(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        amount_out: Balance,
+    ) -> Decimal {
+        self.internal_checked_price(token_in, amount_in, amount_out)
+            .unwrap_or_else(|| env::panic_str("price is too large"))
+    }
+
+    /// Like [`Self::internal_price`], but `None` instead of panicking if `amount_in` is zero
+    /// or the price does not fit into a [`Decimal`].
+    pub(crate) fn internal_checked_price(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+        amount_out: Balance,
+    ) -> Option<Decimal> {
+        if amount_in == 0 {
+            return None;
+        }
+        let (decimals_in, decimals_out) = if *token_in == self.a {
+            (self.a_meta.decimals, self.b_meta.decimals)
+        } else {
+            (self.b_meta.decimals, self.a_meta.decimals)
+        };
+        let amount_out_per_token = (U256::from(amount_out) * U256::exp10(decimals_in.into())
+            / U256::from(amount_in))
+        .try_into()
+        .ok()?;
+        try_to_decimal(amount_out_per_token, decimals_out).map(|price| price.normalize())
+    }
+
+    /// Token out, amount out and fee of a swap of `amount_in` of `token_in`.
+    pub(crate) fn internal_quote_out(
+        &self,
+        token_in: &AccountId,
+        amount_in: Balance,
+    ) -> (AccountId, Balance, Balance) {
+        let (reserve_in, reserve_out) = self.internal_reserves(token_in);
+        assert!(reserve_in > 0 && reserve_out > 0, "pool has no liquidity");
+        let token_out = if *token_in == self.a {
+            self.b.clone()
+        } else {
+            self.a.clone()
+        };
+        let (amount_out, fee_amount) =
+            swap_out(amount_in, reserve_in, reserve_out, self.k, self.fee);
+        (token_out, amount_out, fee_amount)
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_swap_in_is_smallest_input() {
+        for fee in [0, 30, 1_000] {
+            for amount_out in [1, 7, 500, 9_999] {
+                let k = U256::from(200_000_000);
+                let amount_in = swap_in(amount_out, 20_000, 10_000, k, fee);
+                assert!(swap_out(amount_in, 20_000, 10_000, k, fee).0 >= amount_out);
+                assert!(swap_out(amount_in - 1, 20_000, 10_000, k, fee).0 < amount_out);
+            }
+        }
+    }
+
+    #[test]
+    fn test_swap_out_rounds_against_trader() {
+        let k = U256::from(1_000_000);
+        // 1 in would be worth 0.999 out, which rounds to nothing.
+        assert_eq!(swap_out(1, 1_000, 1_000, k, 0), (0, 0));
+        // The fee on 1 rounds up to 1.
+        assert_eq!(swap_out(1, 1_000, 1_000, k, 30), (0, 1));
+        for amount_in in [1, 2, 3, 999, 1_000, 12_345] {
+            let (amount_out, fee_amount) = swap_out(amount_in, 1_000, 1_000, k, 30);
+            let product =
+                U256::from(1_000 + amount_in - fee_amount) * U256::from(1_000 - amount_out);
+            assert!(product >= k);
+        }
+    }
+
+    #[test]
+    fn test_to_decimal() {
+        assert_eq!(to_decimal(2_500_000, 6).normalize().to_string(), "2.5");
+        assert_eq!(to_decimal(7, 0).to_string(), "7");
+        // 1.5e30 with 24 decimals keeps its 28 leading digits.
+        let amount = 15 * 10u128.pow(29);
+        assert_eq!(to_decimal(amount, 24).normalize().to_string(), "1500000");
+    }
+
+    #[test]
+    fn test_mul_div_wide_product() {
+        assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX), u128::MAX);
+        assert_eq!(
+            mul_div(10u128.pow(30), 10u128.pow(24), 10u128.pow(30)),
+            10u128.pow(24)
+        );
+    }
+}
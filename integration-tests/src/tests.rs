@@ -59,7 +59,7 @@ async fn main() -> anyhow::Result<()> {
     )
     .await?;
 
-    test_user_swap(
+    let test_user = test_user_swap(
         &owner,
         &worker,
         &ft_contract_1,
@@ -68,6 +68,19 @@ async fn main() -> anyhow::Result<()> {
     )
     .await?;
 
+    test_route_swap(
+        &owner,
+        &worker,
+        &test_user,
+        &ft_contract_1,
+        &ft_contract_2,
+        &amm_contract,
+        &amm_wallet_account_id,
+    )
+    .await?;
+
+    test_upgrade_pool(&owner, &worker, &amm_contract, &amm_wallet_account_id).await?;
+
     Ok(())
 }
 
@@ -103,31 +116,86 @@ async fn test_init_contracts(
     assert_eq!(ft_2_owner_balance.0, FT_INIT_SUPPLY);
 
     // init amm contract
-    amm.call(worker, "init")
+    assert!(amm
+        .call(worker, "new")
+        .args_json(json!({
+            "owner_id": owner.id(),
+        }))?
+        .gas(GAS_MAX)
+        .transact()
+        .await?
+        .is_success());
+
+    // upload pool code
+    assert!(owner
+        .call(worker, amm.id(), "store_pool_code")
+        .args(AMM_WALLET_WASM.to_vec())
+        .gas(GAS_MAX)
+        .transact()
+        .await?
+        .is_success());
+
+    // create pool for ft_1/ft_2
+    amm.call(worker, "create_pool")
         .args_json(serde_json::json!({
             "a":ft_1.id(),
             "b":ft_2.id(),
+            "fee": 0,
         }))?
         .gas(GAS_MAX)
+        .deposit(POOL_CREATION_DEPOSIT)
         .transact()
         .await?;
 
-    let amm_info: serde_json::Value = owner
-        .call(worker, amm.id(), "state")
-        .args_json(json!({
-            "account_id": owner.id()
-        }))?
-        .transact()
+    let number_of_pools: u64 = amm
+        .view(
+            worker,
+            "get_number_of_pools",
+            json!({}).to_string().into_bytes(),
+        )
         .await?
         .json()?;
-    assert!(amm_info.get("initialized").unwrap().as_bool().unwrap(),);
-    let amm_wallet_account_id_str = amm_info.get("wallet").unwrap().as_str().unwrap();
+    assert_eq!(number_of_pools, 1);
+    let factory_info = amm_factory_info(worker, amm.id()).await?;
+    assert_eq!(
+        factory_info.get("owner_id").unwrap().as_str().unwrap(),
+        owner.id().as_str()
+    );
+    assert_eq!(factory_info.get("number_of_pools").unwrap(), 1);
+    let amm_pool: serde_json::Value = amm
+        .view(
+            worker,
+            "get_pool",
+            json!({
+                "a": ft_2.id(),
+                "b": ft_1.id(),
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(amm_pool.get("status").unwrap().as_str().unwrap(), "Ready");
+    let amm_wallet_account_id_str = amm_pool.get("account_id").unwrap().as_str().unwrap();
     assert_ne!(amm_wallet_account_id_str, "");
+    let expected_account_id: String = amm
+        .view(
+            worker,
+            "get_pool_account_id",
+            json!({
+                "a": ft_2.id(),
+                "b": ft_1.id(),
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(expected_account_id, amm_wallet_account_id_str);
     let amm_wallet_account_id: AccountId = AccountId::from_str(amm_wallet_account_id_str).unwrap();
 
     check_amm_wallet_status(
         worker,
-        owner,
         &amm_wallet_account_id,
         ft_1.id(),
         ft_2.id(),
@@ -166,37 +234,47 @@ async fn test_amm_wallet_owner_deposit(
     amm: &Contract,
     amm_wallet_account_id: &AccountId,
 ) -> anyhow::Result<()> {
-    ft_transfer(
+    ft_transfer_call(
         worker,
         ft_1.id(),
         amm.as_account(),
         amm_wallet_account_id,
         "1000",
-        true,
+        "add_liquidity",
     )
     .await?;
     let amm_wallet_ft_1_balance: U128 =
         ft_balance(worker, owner, ft_1.id(), amm_wallet_account_id).await?;
     assert_eq!(amm_wallet_ft_1_balance, U128::from(1000));
 
-    check_amm_wallet_balance(worker, owner, amm_wallet_account_id, 1000u128, 0u128, 0u128).await?;
+    // deposits are not liquidity until add_liquidity
+    check_amm_wallet_balance(worker, amm_wallet_account_id, 0u128, 0u128, 0u128).await?;
 
-    ft_transfer(
+    ft_transfer_call(
         worker,
         ft_2.id(),
         amm.as_account(),
         amm_wallet_account_id,
         "1000",
-        true,
+        "add_liquidity",
     )
     .await?;
     let amm_wallet_ft_2_balance: U128 =
-        ft_balance(worker, owner, ft_1.id(), amm_wallet_account_id).await?;
+        ft_balance(worker, owner, ft_2.id(), amm_wallet_account_id).await?;
     assert_eq!(amm_wallet_ft_2_balance, U128::from(1000));
 
+    let shares = amm_wallet_add_liquidity(
+        worker,
+        amm.as_account(),
+        amm_wallet_account_id,
+        "1000",
+        "1000",
+    )
+    .await?;
+    assert_eq!(shares, U128::from(1000));
+
     check_amm_wallet_balance(
         worker,
-        owner,
         amm_wallet_account_id,
         1000u128,
         1000u128,
@@ -257,7 +335,7 @@ async fn test_user_swap(
     ft_1: &Contract,
     ft_2: &Contract,
     amm_wallet: &AccountId,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Account> {
     // 1. prepare test account
     let test_user = test_prepare_user(owner, worker, ft_1, ft_2).await?;
 
@@ -266,20 +344,162 @@ async fn test_user_swap(
 
     let amm_wallet_ft_1_balance: U128 = ft_balance(worker, owner, ft_1.id(), amm_wallet).await?;
     assert_eq!(amm_wallet_ft_1_balance, U128::from(1200));
-    let b_balance: u128 = 1000000 / 1200;
+    // the pool keeps k / a_balance rounded up
+    let b_balance: u128 = 1000000u128.div_ceil(1200);
     let amm_wallet_ft_2_balance: U128 = ft_balance(worker, owner, ft_2.id(), amm_wallet).await?;
     assert_eq!(amm_wallet_ft_2_balance, U128::from(b_balance));
 
-    check_amm_wallet_balance(worker, owner, amm_wallet, 1200u128, b_balance, 1000000u128).await?;
+    // k is recomputed from the settled balances
+    let k: u128 = 1200 * b_balance;
+    check_amm_wallet_balance(worker, amm_wallet, 1200u128, b_balance, k).await?;
 
     // swap ft 1 to ft 2
     ft_transfer(worker, ft_2.id(), &test_user, amm_wallet, "300", true).await?;
 
     let amm_wallet_ft_2_balance: U128 = ft_balance(worker, owner, ft_2.id(), amm_wallet).await?;
     assert_eq!(amm_wallet_ft_2_balance, U128::from(b_balance + 300));
-    let a_balance: u128 = 1000000 / (b_balance + 300);
+    let a_balance: u128 = k.div_ceil(b_balance + 300);
     let amm_wallet_ft_1_balance: U128 = ft_balance(worker, owner, ft_1.id(), amm_wallet).await?;
     assert_eq!(amm_wallet_ft_1_balance, U128::from(a_balance));
     println!("\tPassed ✅ test_user_swap",);
+    Ok(test_user)
+}
+
+async fn test_route_swap(
+    owner: &Account,
+    worker: &Worker<Sandbox>,
+    test_user: &Account,
+    ft_1: &Contract,
+    ft_2: &Contract,
+    amm: &Contract,
+    amm_wallet: &AccountId,
+) -> anyhow::Result<()> {
+    // second pool for ft_2/ft_3
+    let ft_3 = worker.dev_deploy(FT_WASM).await?;
+    ft_init(worker, owner, &ft_3).await?;
+    assert!(amm
+        .call(worker, "create_pool")
+        .args_json(json!({
+            "a": ft_2.id(),
+            "b": ft_3.id(),
+            "fee": 0,
+        }))?
+        .gas(GAS_MAX)
+        .deposit(POOL_CREATION_DEPOSIT)
+        .transact()
+        .await?
+        .is_success());
+    let amm_wallet_2: String = amm
+        .view(
+            worker,
+            "get_pool_account_id",
+            json!({
+                "a": ft_2.id(),
+                "b": ft_3.id(),
+            })
+            .to_string()
+            .into_bytes(),
+        )
+        .await?
+        .json()?;
+    let amm_wallet_2 = AccountId::from_str(&amm_wallet_2).unwrap();
+    ft_transfer(worker, ft_3.id(), owner, amm.id(), "1000", false).await?;
+    ft_transfer_call(
+        worker,
+        ft_2.id(),
+        amm.as_account(),
+        &amm_wallet_2,
+        "1000",
+        "add_liquidity",
+    )
+    .await?;
+    ft_transfer_call(
+        worker,
+        ft_3.id(),
+        amm.as_account(),
+        &amm_wallet_2,
+        "1000",
+        "add_liquidity",
+    )
+    .await?;
+    amm_wallet_add_liquidity(worker, amm.as_account(), &amm_wallet_2, "1000", "1000").await?;
+    ft_storage_deposit(worker, owner, ft_3.id(), test_user.id()).await?;
+
+    // ft 1 to ft 3 through both pools
+    let route = |min_amount_out: &str| {
+        json!({
+            "hops": [
+                {"pool_id": amm_wallet, "token_out": ft_2.id()},
+                {"pool_id": amm_wallet_2, "token_out": ft_3.id()},
+            ],
+            "min_amount_out": min_amount_out,
+        })
+        .to_string()
+    };
+    let ft_1_before = ft_balance(worker, owner, ft_1.id(), test_user.id()).await?;
+    ft_transfer_call(worker, ft_1.id(), test_user, amm.id(), "100", &route("1")).await?;
+    let ft_1_after = ft_balance(worker, owner, ft_1.id(), test_user.id()).await?;
+    assert_eq!(ft_1_before.0 - ft_1_after.0, 100);
+    let ft_3_balance = ft_balance(worker, owner, ft_3.id(), test_user.id()).await?;
+    assert!(ft_3_balance.0 > 0);
+    // the factory keeps nothing of the route
+    let amm_ft_2_balance = ft_balance(worker, owner, ft_2.id(), amm.id()).await?;
+    assert_eq!(amm_ft_2_balance, U128::from(1000));
+
+    // the last hop misses min_amount_out, so the intermediate ft 2 is refunded
+    let ft_2_before = ft_balance(worker, owner, ft_2.id(), test_user.id()).await?;
+    ft_transfer_call(
+        worker,
+        ft_1.id(),
+        test_user,
+        amm.id(),
+        "100",
+        &route("1000"),
+    )
+    .await?;
+    let ft_2_after = ft_balance(worker, owner, ft_2.id(), test_user.id()).await?;
+    assert!(ft_2_after.0 > ft_2_before.0);
+    let ft_3_after = ft_balance(worker, owner, ft_3.id(), test_user.id()).await?;
+    assert_eq!(ft_3_after, ft_3_balance);
+    let amm_ft_2_balance = ft_balance(worker, owner, ft_2.id(), amm.id()).await?;
+    assert_eq!(amm_ft_2_balance, U128::from(1000));
+    println!("\tPassed ✅ test_route_swap",);
+    Ok(())
+}
+
+async fn test_upgrade_pool(
+    owner: &Account,
+    worker: &Worker<Sandbox>,
+    amm: &Contract,
+    amm_wallet: &AccountId,
+) -> anyhow::Result<()> {
+    let info_before = amm_wallet_info(worker, amm_wallet).await?;
+    // redeploy the stored code, which keeps the pool's state through migrate
+    let res = owner
+        .call(worker, amm.id(), "upgrade_pool")
+        .args_json(json!({
+            "pool_id": amm_wallet,
+        }))?
+        .gas(GAS_MAX)
+        .transact()
+        .await?;
+    assert!(res.is_success());
+    let pools: Vec<serde_json::Value> = worker
+        .view(amm.id(), "get_pools", json!({}).to_string().into_bytes())
+        .await?
+        .json()?;
+    let pool = pools
+        .iter()
+        .find(|pool| pool.get("account_id").unwrap().as_str().unwrap() == amm_wallet.as_str())
+        .unwrap();
+    let last_upgrade = pool.get("last_upgrade").unwrap();
+    assert_eq!(
+        last_upgrade.get("status").unwrap().as_str().unwrap(),
+        "Succeeded"
+    );
+    assert_eq!(last_upgrade.get("code_hash"), pool.get("code_hash"));
+    let info_after = amm_wallet_info(worker, amm_wallet).await?;
+    assert_eq!(info_after, info_before);
+    println!("\tPassed ✅ test_upgrade_pool",);
     Ok(())
 }
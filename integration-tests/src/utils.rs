@@ -77,6 +77,50 @@ pub async fn ft_transfer(
     Ok(())
 }
 
+pub async fn ft_transfer_call(
+    worker: &Worker<Sandbox>,
+    ft: &AccountId,
+    sender: &Account,
+    receiver: &AccountId,
+    amount: &str,
+    msg: &str,
+) -> anyhow::Result<()> {
+    assert!(sender
+        .call(worker, ft, "ft_transfer_call")
+        .args_json(json!({
+            "receiver_id": receiver,
+            "amount": amount,
+            "msg": msg,
+        }))?
+        .gas(GAS_MAX)
+        .deposit(1)
+        .transact()
+        .await?
+        .is_success());
+    Ok(())
+}
+
+pub async fn amm_wallet_add_liquidity(
+    worker: &Worker<Sandbox>,
+    caller: &Account,
+    amm_wallet_account_id: &AccountId,
+    max_a: &str,
+    max_b: &str,
+) -> anyhow::Result<U128> {
+    let res = caller
+        .call(worker, amm_wallet_account_id, "add_liquidity")
+        .args_json(json!({
+            "max_a": max_a,
+            "max_b": max_b,
+            "min_shares": "0",
+        }))?
+        .gas(GAS_MAX)
+        .transact()
+        .await?;
+    assert!(res.is_success());
+    res.json()
+}
+
 pub async fn ft_balance(
     worker: &Worker<Sandbox>,
     caller: &Account,
@@ -95,32 +139,52 @@ pub async fn ft_balance(
     res.json()
 }
 
+pub async fn amm_factory_info(
+    worker: &Worker<Sandbox>,
+    amm: &AccountId,
+) -> anyhow::Result<serde_json::Value> {
+    let info: serde_json::Value = worker
+        .view(amm, "get_factory_info", json!({}).to_string().into_bytes())
+        .await?
+        .json()?;
+    assert_eq!(info.get("version").unwrap().as_str().unwrap(), "1");
+    Ok(info)
+}
+
+pub async fn amm_wallet_info(
+    worker: &Worker<Sandbox>,
+    amm_wallet_account_id: &AccountId,
+) -> anyhow::Result<serde_json::Value> {
+    let info: serde_json::Value = worker
+        .view(
+            amm_wallet_account_id,
+            "get_pool_info",
+            json!({}).to_string().into_bytes(),
+        )
+        .await?
+        .json()?;
+    assert_eq!(info.get("version").unwrap().as_str().unwrap(), "1");
+    Ok(info)
+}
+
 pub async fn check_amm_wallet_balance(
     worker: &Worker<Sandbox>,
-    caller: &Account,
     amm_wallet_account_id: &AccountId,
     a_balance: u128,
     b_balance: u128,
     k: u128,
 ) -> anyhow::Result<()> {
-    let res = caller
-        .call(worker, amm_wallet_account_id, "state")
-        .args_json(json!({}))?
-        .transact()
-        .await?;
-    assert!(res.is_success());
-    let amm_wallet_state: serde_json::Value = res.json()?;
+    let amm_wallet_info = amm_wallet_info(worker, amm_wallet_account_id).await?;
     assert_eq!(
-        // Balance::from_str(amm_wallet_state.get("a_balance").unwrap().as_str().unwrap())?,
-        amm_wallet_state.get("a_balance").unwrap().to_string(),
+        amm_wallet_info.get("a_reserve").unwrap().as_str().unwrap(),
         a_balance.to_string(),
     );
     assert_eq!(
-        amm_wallet_state.get("b_balance").unwrap().to_string(),
+        amm_wallet_info.get("b_reserve").unwrap().as_str().unwrap(),
         b_balance.to_string(),
     );
     assert_eq!(
-        amm_wallet_state.get("k").unwrap().to_string(),
+        amm_wallet_info.get("k").unwrap().as_str().unwrap(),
         k.to_string(),
     );
     Ok(())
@@ -128,30 +192,23 @@ pub async fn check_amm_wallet_balance(
 
 pub async fn check_amm_wallet_status(
     worker: &Worker<Sandbox>,
-    caller: &Account,
     amm_wallet_account_id: &AccountId,
     a: &AccountId,
     b: &AccountId,
     owner: &AccountId,
 ) -> anyhow::Result<()> {
-    let res = caller
-        .call(worker, amm_wallet_account_id, "state")
-        .args_json(json!({}))?
-        .transact()
-        .await?;
-    assert!(res.is_success());
-    let amm_wallet_state: serde_json::Value = res.json()?;
+    let amm_wallet_info = amm_wallet_info(worker, amm_wallet_account_id).await?;
     assert_eq!(
-        amm_wallet_state.get("a").unwrap().as_str().unwrap(),
+        amm_wallet_info.get("a").unwrap().as_str().unwrap(),
         a.as_str(),
     );
 
     assert_eq!(
-        amm_wallet_state.get("b").unwrap().as_str().unwrap(),
+        amm_wallet_info.get("b").unwrap().as_str().unwrap(),
         b.as_str(),
     );
     assert_eq!(
-        amm_wallet_state.get("owner").unwrap().as_str().unwrap(),
+        amm_wallet_info.get("owner").unwrap().as_str().unwrap(),
         owner.as_str(),
     );
     Ok(())